@@ -1,15 +1,52 @@
 #![allow(dead_code, unused_variables, unused_assignments)]
 use std::{
-    fs::File,
-    io::{self, BufReader, Read},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read},
+    path::Path,
+    process,
 };
 
+use image::RgbaImage;
+
+use compositor::DISPOSAL_UNSPECIFIED;
+
+mod builder;
+mod caption;
+mod compositor;
+mod decoder;
+mod dither;
+mod encoder;
+mod error;
+mod looping;
+mod lzw;
+mod optimize;
+mod palette;
+mod quantize;
+mod resize;
+
+pub use builder::EncodeOptions;
+pub use caption::{CaptionBarStyle, CaptionPosition, CaptionStyle};
+pub use compositor::Compositor;
+pub use decoder::{CompositedFrames, Frame, GifDecoder};
+pub use dither::{DitherMethod, DitherOptions};
+pub use encoder::GifEncoder;
+pub use error::{BlockKind, GifError};
+pub use looping::LoopCount;
+pub use palette::PaletteIndex;
+pub use quantize::Quantizer;
+pub use resize::{ResizeFilter, ResizeMode, ResizeOptions};
+
 #[derive(Clone,Debug)]
 pub struct GIF {
     header: GIFHeader,
     logical_screen_descriptor: LogicalScreenDescriptor,
     global_color_table: Option<GlobalColorTable>,
+    // Extensions that belong to the whole file, such as NETSCAPE looping or XMP metadata, in the
+    // order they appear before the first frame.
+    extensions: Vec<Extension>,
     images: Vec<GIFImage>,
+    // Extensions between the last frame and the trailer.
+    trailing_extensions: Vec<Extension>,
     trailer: Trailer,
 }
 
@@ -76,6 +113,10 @@ pub struct LocalColorTable {
 pub struct ImageData {
     lzw_minimum_code_size: u8,
     sub_blocks: Vec<GIFDataSubBlock>,
+    // Which frame of the file the data was read from and the byte it starts at, so invalid data
+    // can be found. None for data encoded here.
+    frame: Option<usize>,
+    offset: Option<u64>,
 }
 
 #[derive(Clone,Debug)]
@@ -107,7 +148,6 @@ pub struct GCEPackedFields {
 pub struct CommentExtension {
     extension_introducer: u8,
     comment_label: u8,
-    block_size: u8,
     comment_data: Vec<GIFDataSubBlock>,
     block_terminator: u8,
 }
@@ -140,6 +180,23 @@ pub struct ApplicationExtension {
     block_terminator: u8,
 }
 
+#[derive(Clone,Debug)]
+pub struct UnknownExtension {
+    extension_introducer: u8,
+    extension_label: u8,
+    data: Vec<GIFDataSubBlock>,
+    block_terminator: u8,
+}
+
+#[derive(Clone,Debug)]
+pub enum Extension {
+    GraphicControl(GraphicControlExtension),
+    Comment(CommentExtension),
+    PlainText(PlainTextExtension),
+    Application(ApplicationExtension),
+    Unknown(UnknownExtension),
+}
+
 #[derive(Clone,Debug)]
 pub struct Trailer {
     trailer: u8,
@@ -147,10 +204,8 @@ pub struct Trailer {
 
 #[derive(Clone,Debug)]
 pub struct GIFImage {
-    graphic_control_extension: Option<GraphicControlExtension>,
-    comment_extension: Option<CommentExtension>,
-    plain_text_extension: Option<PlainTextExtension>,
-    application_extension: Option<ApplicationExtension>,
+    // Every extension between the previous frame and this frame's image descriptor, in order.
+    extensions: Vec<Extension>,
     image_descriptor: ImageDescriptor,
     local_color_table: Option<LocalColorTable>,
     image_data: ImageData,
@@ -229,574 +284,454 @@ impl GCEPackedFields {
     }
 }
 
-impl GIFDataSubBlock {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.size);
-        bytes.extend_from_slice(&self.data);
-        bytes
+impl GraphicControlExtension {
+    // Transparency is turned on exactly when there is a transparent index.
+    fn new(
+        disposal_method: u8,
+        delay_time: u16,
+        transparent_index: Option<u8>,
+    ) -> GraphicControlExtension {
+        GraphicControlExtension {
+            extension_introducer: EXTENSION_INTRODUCER,
+            graphic_control_label: GRAPHIC_CONTROL_LABEL,
+            block_size: GRAPHIC_CONTROL_BLOCK_SIZE,
+            packed_fields: GCEPackedFields {
+                reserved: 0,
+                disposal_method,
+                user_input_flag: false,
+                transparent_color_flag: transparent_index.is_some(),
+            },
+            delay_time,
+            transparent_color_index: transparent_index.unwrap_or(0),
+            block_terminator: 0,
+        }
     }
-}
 
-pub fn encode_gif(gif: GIF) -> Vec<u8> {
-    let mut bytes = Vec::new();
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.packed_fields
+            .transparent_color_flag
+            .then_some(self.transparent_color_index)
+    }
 
-    bytes.extend_from_slice(&gif.header.signature);
-    bytes.extend_from_slice(&gif.header.version);
+    pub fn set_transparent_index(&mut self, transparent_index: Option<u8>) {
+        self.packed_fields.transparent_color_flag = transparent_index.is_some();
+        self.transparent_color_index = transparent_index.unwrap_or(0);
+    }
+}
 
-    bytes.extend_from_slice(&gif.logical_screen_descriptor.width.to_le_bytes());
-    bytes.extend_from_slice(&gif.logical_screen_descriptor.height.to_le_bytes());
+impl ImageData {
+    // Compresses color indices and splits the result into sub-blocks of at most 255 bytes.
+    pub fn from_indices(lzw_minimum_code_size: u8, indices: &[u8]) -> ImageData {
+        ImageData::from_data(
+            lzw_minimum_code_size,
+            &lzw::encode(lzw_minimum_code_size, indices),
+        )
+    }
 
-    bytes.push(gif.logical_screen_descriptor.packed_fields.to_byte());
+    fn from_data(lzw_minimum_code_size: u8, data: &[u8]) -> ImageData {
+        let sub_blocks = data
+            .chunks(255)
+            .map(|chunk| GIFDataSubBlock {
+                size: chunk.len() as u8,
+                data: chunk.to_vec(),
+            })
+            .collect();
+        ImageData {
+            lzw_minimum_code_size,
+            sub_blocks,
+            frame: None,
+            offset: None,
+        }
+    }
 
-    bytes.push(gif.logical_screen_descriptor.background_color_index);
-    bytes.push(gif.logical_screen_descriptor.pixel_aspect_ratio);
+    // The smallest code size that fits every index of a color table, never less than 2 bits.
+    pub fn minimum_code_size(color_count: usize) -> u8 {
+        let mut code_size = 2;
+        while (1 << code_size) < color_count {
+            code_size += 1;
+        }
+        code_size
+    }
 
-    if let Some(global_color_table) = gif.global_color_table {
-        for color in global_color_table.colors {
-            bytes.push(color.red);
-            bytes.push(color.green);
-            bytes.push(color.blue);
+    fn data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for sub_block in &self.sub_blocks {
+            data.extend_from_slice(&sub_block.data);
         }
+        data
+    }
+}
+
+impl GIFImage {
+    pub fn graphic_control_extension(&self) -> Option<&GraphicControlExtension> {
+        self.extensions
+            .iter()
+            .find_map(|extension| match extension {
+                Extension::GraphicControl(graphic_control_extension) => {
+                    Some(graphic_control_extension)
+                }
+                _ => None,
+            })
     }
 
-    for image in gif.images {
-        if let Some(graphic_control_extension) = image.graphic_control_extension {
-            bytes.push(graphic_control_extension.extension_introducer);
-            bytes.push(graphic_control_extension.graphic_control_label);
-            bytes.push(graphic_control_extension.block_size);
-            bytes.push(graphic_control_extension.packed_fields.to_byte());
-            bytes.extend_from_slice(&graphic_control_extension.delay_time.to_le_bytes());
-            bytes.push(graphic_control_extension.transparent_color_index);
-            bytes.push(graphic_control_extension.block_terminator);
+    pub fn graphic_control_extension_mut(&mut self) -> Option<&mut GraphicControlExtension> {
+        self.extensions
+            .iter_mut()
+            .find_map(|extension| match extension {
+                Extension::GraphicControl(graphic_control_extension) => {
+                    Some(graphic_control_extension)
+                }
+                _ => None,
+            })
+    }
+
+    // The frame's graphic control extension, adding one that changes nothing when it has none.
+    fn graphic_control_extension_or_default(&mut self) -> &mut GraphicControlExtension {
+        if self.graphic_control_extension().is_none() {
+            self.set_graphic_control_extension(GraphicControlExtension::new(
+                DISPOSAL_UNSPECIFIED,
+                0,
+                None,
+            ));
         }
-        if let Some(comment_extension) = image.comment_extension {
-            bytes.push(comment_extension.extension_introducer);
-            bytes.push(comment_extension.comment_label);
-            bytes.push(comment_extension.block_size);
-            for subblock in comment_extension.comment_data {
-                bytes.extend_from_slice(&subblock.to_bytes());
-            }
-            bytes.push(comment_extension.block_terminator);
+        self.graphic_control_extension_mut().unwrap()
+    }
+
+    // The index drawn as transparent, None without a graphic control extension.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.graphic_control_extension()
+            .and_then(GraphicControlExtension::transparent_index)
+    }
+
+    // The local color table, or else the global one.
+    fn color_table<'a>(
+        &'a self,
+        global_color_table: Option<&'a GlobalColorTable>,
+    ) -> Result<&'a [Color], GifError> {
+        match (&self.local_color_table, global_color_table) {
+            (Some(local_color_table), _) => Ok(&local_color_table.colors),
+            (None, Some(global_color_table)) => Ok(&global_color_table.colors),
+            (None, None) => Err(GifError::MissingColorTable),
         }
-        if let Some(plain_text_extension) = image.plain_text_extension {
-            bytes.push(plain_text_extension.extension_introducer);
-            bytes.push(plain_text_extension.plain_text_label);
-            bytes.push(plain_text_extension.block_size);
-            bytes.extend_from_slice(&plain_text_extension.text_grid_left_position.to_le_bytes());
-            bytes.extend_from_slice(&plain_text_extension.text_grid_top_position.to_le_bytes());
-            bytes.extend_from_slice(&plain_text_extension.text_grid_width.to_le_bytes());
-            bytes.extend_from_slice(&plain_text_extension.text_grid_height.to_le_bytes());
-            bytes.push(plain_text_extension.character_cell_width);
-            bytes.push(plain_text_extension.character_cell_height);
-            bytes.push(plain_text_extension.text_foreground_color_index);
-            bytes.push(plain_text_extension.text_background_color_index);
-            for subblock in plain_text_extension.text_data {
-                bytes.extend_from_slice(&subblock.to_bytes());
-            }
-            bytes.push(plain_text_extension.block_terminator);
+    }
+
+    // Delay in hundredths of a second, 0 without a graphic control extension.
+    pub fn delay(&self) -> u16 {
+        self.graphic_control_extension()
+            .map(|graphic_control_extension| graphic_control_extension.delay_time)
+            .unwrap_or(0)
+    }
+
+    // Replaces the frame's graphic control extension, or adds it right before the image when the
+    // frame has none.
+    pub fn set_graphic_control_extension(
+        &mut self,
+        graphic_control_extension: GraphicControlExtension,
+    ) {
+        match self.graphic_control_extension_mut() {
+            Some(existing) => *existing = graphic_control_extension,
+            None => self
+                .extensions
+                .push(Extension::GraphicControl(graphic_control_extension)),
         }
-        if let Some(application_extension) = image.application_extension {
-            bytes.push(application_extension.extension_introducer);
-            bytes.push(application_extension.extension_label);
-            bytes.push(application_extension.block_size);
-            bytes.extend_from_slice(&application_extension.application_identifier);
-            bytes.extend_from_slice(&application_extension.application_authentication_code);
-            for subblock in application_extension.application_data {
-                bytes.extend_from_slice(&subblock.to_bytes());
-            }
-            bytes.push(application_extension.block_terminator);
+    }
+
+    // Decompresses the frame into one palette index per pixel, in row-major order. Interlaced
+    // frames are returned deinterlaced, and data that runs short is padded with index 0.
+    pub fn indices(&self) -> Result<Vec<u8>, GifError> {
+        let width = self.image_descriptor.width as usize;
+        let height = self.image_descriptor.height as usize;
+
+        let mut indices = lzw::decode(
+            self.image_data.lzw_minimum_code_size,
+            &self.image_data.data(),
+        )
+        .map_err(|error| match error {
+            GifError::InvalidLzw { message, .. } => GifError::InvalidLzw {
+                frame: self.image_data.frame,
+                offset: self.image_data.offset,
+                message,
+            },
+            error => error,
+        })?;
+        indices.resize(width * height, 0);
+
+        if !self.image_descriptor.packed_fields.interlace_flag {
+            return Ok(indices);
         }
-        bytes.push(image.image_descriptor.separator);
-        bytes.extend_from_slice(&image.image_descriptor.left_position.to_le_bytes());
-        bytes.extend_from_slice(&image.image_descriptor.top_position.to_le_bytes());
-        bytes.extend_from_slice(&image.image_descriptor.width.to_le_bytes());
-        bytes.extend_from_slice(&image.image_descriptor.height.to_le_bytes());
-        bytes.push(image.image_descriptor.packed_fields.to_byte());
-        if let Some(local_color_table) = image.local_color_table {
-            for color in local_color_table.colors {
-                bytes.push(color.red);
-                bytes.push(color.green);
-                bytes.push(color.blue);
+
+        let mut deinterlaced = vec![0; width * height];
+        let mut rows = indices.chunks_exact(width.max(1));
+        for (start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)] {
+            for y in (start..height).step_by(step) {
+                if let Some(row) = rows.next() {
+                    deinterlaced[y * width..(y + 1) * width].copy_from_slice(row);
+                }
             }
         }
-        bytes.push(image.image_data.lzw_minimum_code_size);
-        for subblock in image.image_data.sub_blocks {
-            bytes.extend_from_slice(&subblock.to_bytes());
-        }
-        bytes.push(0);
+        Ok(deinterlaced)
     }
-
-    bytes
 }
 
-#[derive(Debug)]
-pub struct GIFError {
-    message: String,
+pub fn encode_gif(gif: GIF) -> Result<Vec<u8>, GifError> {
+    gif.to_bytes()
 }
 
+// Browsers play delays below 2 hundredths of a second as 10.
+const MIN_DELAY: u16 = 2;
+const BROWSER_DEFAULT_DELAY: u16 = 10;
+
 const GIF_SIGNATURE: [u8; 3] = [0x47, 0x49, 0x46];
 const GIF_87A_VERSION: [u8; 3] = [0x38, 0x37, 0x61];
 const GIF_89A_VERSION: [u8; 3] = [0x38, 0x39, 0x61];
 
-pub fn file_to_gif(file_path: &str) -> Result<GIF, GIFError> {
-    let file = File::open(file_path).expect("Unable to open file");
-    let mut reader = BufReader::new(file);
-    let mut gif_header_bytes: [u8; 6] = [0; 6];
-    reader
-        .read_exact(&mut gif_header_bytes)
-        .expect("Unable to read gif header");
-    if gif_header_bytes[0..3] != GIF_SIGNATURE {
-        return Result::Err(GIFError {
-            message: "Invalid gif signature".to_string(),
-        });
-    };
-    let gif_header = GIFHeader {
-        signature: [
-            gif_header_bytes[0],
-            gif_header_bytes[1],
-            gif_header_bytes[2],
-        ],
-        version: [
-            gif_header_bytes[3],
-            gif_header_bytes[4],
-            gif_header_bytes[5],
-        ],
-    };
-    let mut logical_screen_descriptor_bytes: [u8; 7] = [0; 7];
-    reader
-        .read_exact(&mut logical_screen_descriptor_bytes)
-        .expect("Unable to read logical screen descriptor");
-    let logical_screen_descriptor = LogicalScreenDescriptor {
-        width: u16::from_le_bytes([
-            logical_screen_descriptor_bytes[0],
-            logical_screen_descriptor_bytes[1],
-        ]),
-        height: u16::from_le_bytes([
-            logical_screen_descriptor_bytes[2],
-            logical_screen_descriptor_bytes[3],
-        ]),
-        packed_fields: LSDPackedFields::from_byte(logical_screen_descriptor_bytes[4]),
-        background_color_index: logical_screen_descriptor_bytes[5],
-        pixel_aspect_ratio: logical_screen_descriptor_bytes[6],
-    };
-    let global_color_table = if logical_screen_descriptor
-        .packed_fields
-        .global_color_table_flag
-    {
-        let mut colors = Vec::new();
-        let chunk_length = 2u16.pow(
-            (logical_screen_descriptor
-                .packed_fields
-                .size_of_global_color_table
-                + 1)
-            .into(),
+const EXTENSION_INTRODUCER: u8 = 0x21;
+const IMAGE_SEPARATOR: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+
+const GRAPHIC_CONTROL_LABEL: u8 = 0xF9;
+const COMMENT_LABEL: u8 = 0xFE;
+const PLAIN_TEXT_LABEL: u8 = 0x01;
+const APPLICATION_LABEL: u8 = 0xFF;
+
+const GRAPHIC_CONTROL_BLOCK_SIZE: u8 = 4;
+const PLAIN_TEXT_BLOCK_SIZE: u8 = 12;
+const APPLICATION_BLOCK_SIZE: u8 = 11;
+
+pub fn file_to_gif(file_path: &str) -> Result<GIF, GifError> {
+    GIF::from_reader(File::open(file_path)?)
+}
+
+impl GIF {
+    pub fn from_file(file_path: &str) -> Result<GIF, GifError> {
+        file_to_gif(file_path)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<GIF, GifError> {
+        decoder::read_gif(BufReader::new(reader))
+    }
+
+    // Slices are read directly, without the buffering `from_reader` adds.
+    pub fn from_bytes(bytes: &[u8]) -> Result<GIF, GifError> {
+        decoder::read_gif(bytes)
+    }
+
+    // The gif is streamed into a temporary file next to the target and renamed over it once
+    // complete, so a failure never leaves a truncated file behind.
+    pub fn save(&self, file_path: &str) -> Result<(), GifError> {
+        let path = Path::new(file_path);
+        let file_name = path.file_name().ok_or_else(|| GifError::InvalidInput {
+            message: format!("{} is not a file path", file_path),
+        })?;
+        let temporary_path = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            file_name.to_string_lossy(),
+            process::id()
+        ));
+
+        let written = self
+            .write_file(&temporary_path)
+            .and_then(|()| Ok(fs::rename(&temporary_path, path)?));
+        if written.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+        written
+    }
+
+    fn write_file(&self, path: &Path) -> Result<(), GifError> {
+        let writer = self.write_to(BufWriter::new(File::create(path)?))?;
+        writer.into_inner().map_err(|error| error.into_error())?.sync_all()?;
+        Ok(())
+    }
+    // Every frame as a browser would show it, composited onto the full logical screen.
+    pub fn frames(&self) -> Result<Vec<RgbaImage>, GifError> {
+        let mut compositor = Compositor::new(
+            self.logical_screen_descriptor.width,
+            self.logical_screen_descriptor.height,
         );
-        for _ in 0..chunk_length {
-            let mut color_bytes = [0u8; 3];
-            reader
-                .read_exact(&mut color_bytes)
-                .expect("Unable to read color");
-            colors.push(Color {
-                red: color_bytes[0],
-                green: color_bytes[1],
-                blue: color_bytes[2],
+        self.images
+            .iter()
+            .map(|image| compositor.draw(image, self.global_color_table.as_ref()))
+            .collect()
+    }
+
+    // Frame delays in hundredths of a second, 0 for frames without a graphic control extension.
+    pub fn delays(&self) -> Vec<u16> {
+        self.images.iter().map(GIFImage::delay).collect()
+    }
+
+    // Re-encoded gifs start without extensions, so the file-level blocks of the source, such as
+    // looping and comments, are carried over. Application extensions stored with the first frame,
+    // like the looping block some encoders write after the first graphic control extension,
+    // describe the whole file too.
+    fn with_file_extensions_of(mut self, source: &GIF) -> GIF {
+        let is_application =
+            |extension: &&Extension| matches!(extension, Extension::Application(_));
+        self.extensions = source.extensions.clone();
+        let first_frame_has_them = self.images.first().is_some_and(|image| {
+            image
+                .extensions
+                .iter()
+                .any(|extension| is_application(&extension))
+        });
+        if !first_frame_has_them {
+            if let Some(image) = source.images.first() {
+                self.extensions
+                    .extend(image.extensions.iter().filter(is_application).cloned());
+            }
+        }
+        self.trailing_extensions = source.trailing_extensions.clone();
+        self.set_loop_count(source.loop_count());
+        self
+    }
+
+    // Plays the animation backwards. Frames may only store what changed since the frame before
+    // them, so the composited frames are reversed and re-encoded. Each frame keeps its own delay.
+    pub fn reverse(&self) -> Result<GIF, GifError> {
+        let mut frames = self.frames()?;
+        let mut delays = self.delays();
+        frames.reverse();
+        delays.reverse();
+
+        let new_gif = GIF::from_frames(&frames, &delays, &EncodeOptions::default())?;
+        Ok(new_gif.with_file_extensions_of(self))
+    }
+
+    // Plays the gif `factor` times as fast. Browsers show frames for at least 2 hundredths of a
+    // second, so when a frame would be shorter it is dropped and its time given to the frame
+    // before it. Delays below that floor count as the 10 hundredths browsers use for them.
+    pub fn speed(&self, factor: f32) -> Result<GIF, GifError> {
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(GifError::InvalidInput {
+                message: format!("Invalid speed factor: {}", factor),
             });
         }
-        Some(GlobalColorTable { colors })
-    } else {
-        None
-    };
-    let mut images: Vec<GIFImage> = Vec::new();
-    loop {
-        let mut image_frame = GIFImage {
-            image_descriptor: ImageDescriptor {
-                separator: 0x2C,
-                left_position: 0,
-                top_position: 0,
-                width: 0,
-                height: 0,
-                packed_fields: IDPackedFields {
-                    local_color_table_flag: false,
-                    interlace_flag: false,
-                    sort_flag: false,
-                    reserved: 0,
-                    size_of_local_color_table: 0,
-                },
-            },
-            local_color_table: None,
-            image_data: ImageData {
-                lzw_minimum_code_size: 0,
-                sub_blocks: Vec::new(),
-            },
-            graphic_control_extension: None,
-            comment_extension: None,
-            plain_text_extension: None,
-            application_extension: None,
-        };
-
-        let mut separator: [u8; 1] = [0; 1];
-        loop {
-            reader
-                .read_exact(&mut separator)
-                .expect("Unable to read separator");
-            match separator[0] {
-                0x2C => {
-                    // Image Descriptor
-                    // println!("Image Descriptor");
-                    image_frame.image_descriptor.separator = 0x2C;
-                    let mut image_descriptor_bytes: [u8; 9] = [0; 9];
-                    reader
-                        .read_exact(&mut image_descriptor_bytes)
-                        .expect("Unable to read image descriptor");
-                    image_frame.image_descriptor.left_position =
-                        u16::from_le_bytes([image_descriptor_bytes[0], image_descriptor_bytes[1]]);
-                    image_frame.image_descriptor.top_position =
-                        u16::from_le_bytes([image_descriptor_bytes[2], image_descriptor_bytes[3]]);
-                    image_frame.image_descriptor.width =
-                        u16::from_le_bytes([image_descriptor_bytes[4], image_descriptor_bytes[5]]);
-                    image_frame.image_descriptor.height =
-                        u16::from_le_bytes([image_descriptor_bytes[6], image_descriptor_bytes[7]]);
-                    image_frame.image_descriptor.packed_fields =
-                        IDPackedFields::from_byte(image_descriptor_bytes[8]);
-                    if image_frame
-                        .image_descriptor
-                        .packed_fields
-                        .local_color_table_flag
-                    {
-                        // println!("...with a Local Color Table");
-                        let mut colors = Vec::new();
-                        let chunk_length = 2u16.pow(
-                            (image_frame
-                                .image_descriptor
-                                .packed_fields
-                                .size_of_local_color_table
-                                + 1)
-                            .into(),
-                        );
-                        for _ in 0..chunk_length {
-                            let mut color_bytes = [0u8; 3];
-                            reader
-                                .read_exact(&mut color_bytes)
-                                .expect("Unable to read color");
-                            colors.push(Color {
-                                red: color_bytes[0],
-                                green: color_bytes[1],
-                                blue: color_bytes[2],
-                            });
-                        }
-                        image_frame.local_color_table = Some(LocalColorTable { colors });
-                    }
-                    let mut lzw_minimum_code_size: [u8; 1] = [0; 1];
-                    reader
-                        .read_exact(&mut lzw_minimum_code_size)
-                        .expect("Unable to read lzw minimum code size");
-                    image_frame.image_data.lzw_minimum_code_size = lzw_minimum_code_size[0];
-                    let mut sub_block_size: [u8; 1] = [0; 1];
-                    loop {
-                        reader
-                            .read_exact(&mut sub_block_size)
-                            .expect("Unable to read sub block size");
-                        if sub_block_size[0] == 0 {
-                            break;
-                        }
-                        let mut sub_block_data: Vec<u8> = Vec::new();
-                        for _ in 0..sub_block_size[0] {
-                            let mut sub_block_byte: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut sub_block_byte)
-                                .expect("Unable to read sub block byte");
-                            sub_block_data.push(sub_block_byte[0]);
-                        }
-                        image_frame.image_data.sub_blocks.push(GIFDataSubBlock {
-                            size: sub_block_size[0],
-                            data: sub_block_data,
-                        });
-                    }
-                    images.push(image_frame);
-                    break;
-                }
-                0x21 => {
-                    // Extension
-                    let mut label: [u8; 1] = [0; 1];
-                    reader
-                        .read_exact(&mut label)
-                        .expect("Unable to read graphic control label");
-                    match label[0] {
-                        0xF9 => {
-                            // Graphic Control Extension
-                            // println!("Graphic Control Extension");
-                            let mut block_size: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut block_size)
-                                .expect("Unable to read block size");
-                            let mut packed_fields: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut packed_fields)
-                                .expect("Unable to read packed fields");
-                            let mut delay_time: [u8; 2] = [0; 2];
-                            reader
-                                .read_exact(&mut delay_time)
-                                .expect("Unable to read delay time");
-                            let mut transparent_color_index: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut transparent_color_index)
-                                .expect("Unable to read transparent color index");
-                            let mut block_terminator: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut block_terminator)
-                                .expect("Unable to read block terminator");
-                            image_frame.graphic_control_extension = Some(GraphicControlExtension {
-                                extension_introducer: 0x21,
-                                graphic_control_label: 0xF9,
-                                block_size: block_size[0],
-                                packed_fields: GCEPackedFields::from_byte(packed_fields[0]),
-                                delay_time: u16::from_le_bytes([delay_time[0], delay_time[1]]),
-                                transparent_color_index: transparent_color_index[0],
-                                block_terminator: block_terminator[0],
-                            });
-                        }
-                        0xFE => {
-                            // Comment Extension
-                            // println!("Comment Extension");
-                            let mut block_size: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut block_size)
-                                .expect("Unable to read block size");
-                            let mut comment_data: Vec<GIFDataSubBlock> = Vec::new();
-                            loop {
-                                let mut subblock_size: [u8; 1] = [0; 1];
-                                reader
-                                    .read_exact(&mut subblock_size)
-                                    .expect("Unable to read comment byte");
-                                if subblock_size[0] == 0 {
-                                    break;
-                                }
-                                let mut subblock_data: Vec<u8> = Vec::new();
-                                for _ in 0..subblock_size[0] {
-                                    let mut subblock_byte: [u8; 1] = [0; 1];
-                                    reader
-                                        .read_exact(&mut subblock_byte)
-                                        .expect("Unable to read comment byte");
-                                    subblock_data.push(subblock_byte[0]);
-                                }
-                                comment_data.push(GIFDataSubBlock {
-                                    size: subblock_size[0],
-                                    data: subblock_data,
-                                });
-                            }
-                            image_frame.comment_extension = Some(CommentExtension {
-                                extension_introducer: 0x21,
-                                comment_label: 0xFE,
-                                block_size: block_size[0],
-                                comment_data,
-                                block_terminator: 0x0,
-                            });
-                        }
-                        0x01 => {
-                            // Plain Text Extension
-                            // println!("Plain Text Extension");
-                            let mut block_size: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut block_size)
-                                .expect("Unable to read block size");
-                            let mut text_grid_left_position: [u8; 2] = [0; 2];
-                            reader
-                                .read_exact(&mut text_grid_left_position)
-                                .expect("Unable to read text grid left position");
-                            let mut text_grid_top_position: [u8; 2] = [0; 2];
-                            reader
-                                .read_exact(&mut text_grid_top_position)
-                                .expect("Unable to read text grid top position");
-                            let mut text_grid_width: [u8; 2] = [0; 2];
-                            reader
-                                .read_exact(&mut text_grid_width)
-                                .expect("Unable to read text grid width");
-                            let mut text_grid_height: [u8; 2] = [0; 2];
-                            reader
-                                .read_exact(&mut text_grid_height)
-                                .expect("Unable to read text grid height");
-                            let mut character_cell_width: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut character_cell_width)
-                                .expect("Unable to read character cell width");
-                            let mut character_cell_height: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut character_cell_height)
-                                .expect("Unable to read character cell height");
-                            let mut text_foreground_color_index: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut text_foreground_color_index)
-                                .expect("Unable to read text foreground color index");
-                            let mut text_background_color_index: [u8; 1] = [0; 1];
-                            reader
-                                .read_exact(&mut text_background_color_index)
-                                .expect("Unable to read text background color index");
-                            let mut text_data: Vec<GIFDataSubBlock> = Vec::new();
-                            loop {
-                                let mut subblock_size: [u8; 1] = [0; 1];
-                                reader
-                                    .read_exact(&mut subblock_size)
-                                    .expect("Unable to read text byte");
-                                if subblock_size[0] == 0 {
-                                    break;
-                                }
-                                let mut block_data: Vec<u8> = Vec::new();
-                                for _ in 0..subblock_size[0] {
-                                    let mut subblock_byte: [u8; 1] = [0; 1];
-                                    reader
-                                        .read_exact(&mut subblock_byte)
-                                        .expect("Unable to read text byte");
-                                    block_data.push(subblock_byte[0]);
-                                }
-                                text_data.push(GIFDataSubBlock {
-                                    size: subblock_size[0],
-                                    data: block_data,
-                                });
-                            }
-                            image_frame.plain_text_extension = Some(PlainTextExtension {
-                                extension_introducer: 0x21,
-                                plain_text_label: 0x01,
-                                block_size: block_size[0],
-                                text_grid_left_position: u16::from_le_bytes([
-                                    text_grid_left_position[0],
-                                    text_grid_left_position[1],
-                                ]),
-                                text_grid_top_position: u16::from_le_bytes([
-                                    text_grid_top_position[0],
-                                    text_grid_top_position[1],
-                                ]),
-                                text_grid_width: u16::from_le_bytes([
-                                    text_grid_width[0],
-                                    text_grid_width[1],
-                                ]),
-                                text_grid_height: u16::from_le_bytes([
-                                    text_grid_height[0],
-                                    text_grid_height[1],
-                                ]),
-                                character_cell_width: character_cell_width[0],
-                                character_cell_height: character_cell_height[0],
-                                text_foreground_color_index: text_foreground_color_index[0],
-                                text_background_color_index: text_background_color_index[0],
-                                text_data,
-                                block_terminator: 0,
-                            });
-                        }
-                        0xFF => {
-                            // Application Extension
-                            // println!("Application Extension");
-                            let mut block_size: [u8; 1] = [0; 1];
-                            let mut application_identifier: [u8; 8] = [0; 8];
-                            let mut application_authentication_code: [u8; 3] = [0; 3];
-                            reader
-                                .read_exact(&mut block_size)
-                                .expect("Unable to read block size");
-                            reader
-                                .read_exact(&mut application_identifier)
-                                .expect("Unable to read application identifier");
-                            reader
-                                .read_exact(&mut application_authentication_code)
-                                .expect("Unable to read application authentication code");
-                            // println!(
-                            //     "Application Identifier: {}\nAuthentication Code: {}\nBlock Size: {}",
-                            //     String::from_utf8_lossy(&application_identifier),
-                            //     String::from_utf8_lossy(&application_authentication_code),
-                            //     block_size[0]
-                            // );
-                            let mut application_data: Vec<GIFDataSubBlock> = Vec::new();
-                            loop {
-                                let mut subblock_size: [u8; 1] = [0; 1];
-                                reader
-                                    .read_exact(&mut subblock_size)
-                                    .expect("Unable to subblock size");
-                                if subblock_size[0] == 0 {
-                                    break;
-                                }
-                                let mut block_data: Vec<u8> = Vec::new();
-                                for _ in 0..subblock_size[0] {
-                                    let mut subblock_byte: [u8; 1] = [0; 1];
-                                    reader
-                                        .read_exact(&mut subblock_byte)
-                                        .expect("Unable to read application byte");
-                                    block_data.push(subblock_byte[0]);
-                                }
-                                application_data.push(GIFDataSubBlock {
-                                    size: subblock_size[0],
-                                    data: block_data,
-                                });
-                            }
-                            // for subblock in &application_data {
-                            //     println!(
-                            //         "Subblock Size: {}\nSubblock Data: {:X?}\nWhich has a length of {}",
-                            //         subblock.size,
-                            //         &subblock.data,
-                            //         subblock.data.len()
-                            //     );
-                            // }
-                            // println!("There are {} subblocks", application_data.len());
-                            image_frame.application_extension = Some(ApplicationExtension {
-                                extension_introducer: 0x21,
-                                extension_label: 0xFF,
-                                block_size: block_size[0],
-                                application_identifier,
-                                application_authentication_code,
-                                application_data,
-                                block_terminator: 0,
-                            });
-                        }
-                        _ => {
-                            return Result::Err(GIFError {
-                                message: "Invalid extension label".to_string(),
-                            });
-                        }
-                    }
-                }
-                0x3B => {
-                    break;
-                }
-                _ => {
-                    return Result::Err(GIFError {
-                        message: format!("Invalid separator: {}", separator[0]),
-                    });
+
+        let scaled: Vec<f32> = self
+            .delays()
+            .into_iter()
+            .map(|delay| {
+                let delay = if delay < MIN_DELAY {
+                    BROWSER_DEFAULT_DELAY
+                } else {
+                    delay
+                };
+                delay as f32 / factor
+            })
+            .collect();
+
+        // Frames are grouped until each group lasts at least the minimum delay. Rounding errors
+        // are carried over so the total duration stays right.
+        let mut kept: Vec<(usize, u16)> = Vec::new();
+        let mut group_start = None;
+        let mut group_time = 0.0;
+        let mut rounding_error = 0.0;
+        for (i, &time) in scaled.iter().enumerate() {
+            group_start.get_or_insert(i);
+            group_time += time;
+            if group_time + rounding_error >= MIN_DELAY as f32 {
+                let delay = (group_time + rounding_error).round().min(u16::MAX as f32);
+                rounding_error += group_time - delay;
+                kept.push((group_start.take().unwrap(), delay as u16));
+                group_time = 0.0;
+            }
+        }
+        if group_start.is_some() {
+            match kept.last_mut() {
+                Some((_, delay)) => {
+                    *delay = (*delay as f32 + group_time + rounding_error).round() as u16
                 }
+                None => kept.push((0, MIN_DELAY)),
             }
         }
-        if separator[0] == 0x3B {
-            break;
+
+        if kept.len() == self.images.len() {
+            let mut new_gif = self.clone();
+            for (image, &(_, delay)) in new_gif.images.iter_mut().zip(&kept) {
+                image.graphic_control_extension_or_default().delay_time = delay;
+            }
+            return Ok(new_gif);
         }
-    }
 
-    Result::Ok(GIF {
-        header: gif_header,
-        logical_screen_descriptor,
-        global_color_table,
-        images,
-        trailer: Trailer { trailer: 0x3B },
-    })
+        // Later frames may only store what changed since the dropped ones, so the kept frames are
+        // composited and re-encoded.
+        let frames = self.frames()?;
+        let kept_frames: Vec<RgbaImage> = kept.iter().map(|&(i, _)| frames[i].clone()).collect();
+        let delays: Vec<u16> = kept.iter().map(|&(_, delay)| delay).collect();
+        let new_gif = GIF::from_frames(&kept_frames, &delays, &EncodeOptions::default())?;
+        Ok(new_gif.with_file_extensions_of(self))
+    }
 }
 
-impl GIF {
-    pub fn from_file(file_path: &str) -> GIF {
-        file_to_gif(file_path).expect("Unable to read gif file")
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    // Solid frames of distinct colors, so each frame can be told apart after re-encoding.
+    fn solid_frames(count: u8) -> Vec<RgbaImage> {
+        (0..count)
+            .map(|i| RgbaImage::from_pixel(8, 8, Rgba([i * 40, 255 - i * 40, 100, 255])))
+            .collect()
     }
 
-    pub fn save(&self, file_path: &str) {
-        let bytes = encode_gif(self.clone());
-        std::fs::write(file_path, bytes).expect("Unable to write file")
+    fn animation(delays: &[u16]) -> GIF {
+        let frames = solid_frames(delays.len() as u8);
+        GIF::from_frames(&frames, delays, &EncodeOptions::default()).unwrap()
     }
-    pub fn reverse(&self) -> GIF {
-        let mut new_gif = self.clone();
 
-        new_gif.images.reverse();
+    #[test]
+    fn speed_keeps_every_frame_when_delays_stay_long_enough() {
+        let gif = animation(&[10, 20, 30, 40]);
+        let faster = gif.speed(2.0).unwrap();
+        assert_eq!(faster.delays(), [5, 10, 15, 20]);
+        assert!(faster.frames().unwrap() == gif.frames().unwrap());
+    }
+
+    #[test]
+    fn speed_drops_frames_below_the_minimum_delay() {
+        let gif = animation(&[3; 6]);
+        let faster = gif.speed(4.0).unwrap();
+
+        // Each frame would last 0.75, so frames are merged until their group reaches 2, with the
+        // rounding carried into the next group.
+        assert_eq!(faster.delays(), [2, 3]);
+        let total: u16 = faster.delays().iter().sum();
+        assert!((total as f32 - 18.0 / 4.0).abs() <= 0.5);
 
-        new_gif
+        let frames = gif.frames().unwrap();
+        assert!(faster.frames().unwrap() == [frames[0].clone(), frames[3].clone()]);
     }
-    pub fn resize(&self, width: u16, height: u16) -> GIF {
-        let mut new_gif = self.clone();
 
-        new_gif.logical_screen_descriptor.width = width;
-        new_gif.logical_screen_descriptor.height = height;
+    #[test]
+    fn speed_plays_the_shortest_delays_as_browsers_do() {
+        let gif = animation(&[0, 1, 10]);
+        assert_eq!(gif.speed(1.0).unwrap().delays(), [10, 10, 10]);
+    }
+
+    #[test]
+    fn speed_adds_graphic_control_extensions_to_frames_without_one() {
+        let mut gif = animation(&[10, 10]);
+        for image in &mut gif.images {
+            image.extensions.clear();
+        }
+        let faster = gif.speed(2.0).unwrap();
+        assert!(faster
+            .images
+            .iter()
+            .all(|image| image.graphic_control_extension().is_some()));
+        assert_eq!(faster.delays(), [5, 5]);
+    }
 
-        new_gif
+    #[test]
+    fn speed_rejects_invalid_factors() {
+        let gif = animation(&[10]);
+        for factor in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                gif.speed(factor),
+                Err(GifError::InvalidInput { .. })
+            ));
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,631 @@
+use std::collections::{HashMap, HashSet};
+
+use image::RgbaImage;
+
+use super::compositor::{DISPOSAL_NONE, DISPOSAL_RESTORE_BACKGROUND};
+use super::dither::{dither, DitherOptions};
+use super::palette::PaletteIndex;
+use super::quantize::{histogram, Quantizer};
+use super::{
+    Color, Extension, GCEPackedFields, GIFHeader, GIFImage, GifError, GlobalColorTable,
+    GraphicControlExtension, IDPackedFields, ImageData, ImageDescriptor, LSDPackedFields,
+    LocalColorTable, LogicalScreenDescriptor, Trailer, GIF, GIF_89A_VERSION, GIF_SIGNATURE,
+    IMAGE_SEPARATOR,
+};
+
+// One color table slot is always kept free for the transparent index.
+const MAX_OPAQUE_COLORS: usize = 255;
+
+pub struct EncodeOptions {
+    // Pixels with an alpha below this value are written as transparent.
+    pub alpha_threshold: u8,
+    // Only store the rectangle that changed since the previous frame, with unchanged pixels
+    // inside it made transparent.
+    pub optimize: bool,
+    // How frames with more colors than a table can hold are reduced to a palette.
+    pub quantizer: Quantizer,
+    // How pixels are mapped onto a palette that does not hold their exact colors.
+    pub dither: DitherOptions,
+    // Lets LZW runs take on colors up to this far off in RGB for smaller files, 0 is lossless.
+    // See `GIF::compress_lossy`.
+    pub lossy: u32,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            alpha_threshold: 128,
+            optimize: true,
+            quantizer: Quantizer::default(),
+            dither: DitherOptions::default(),
+            lossy: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rectangle {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// A frame mapped onto its palette, covering the whole canvas. `None` pixels are transparent.
+struct QuantizedFrame {
+    pixels: Vec<Option<[u8; 3]>>,
+    indices: Vec<u8>,
+    local_colors: Option<Vec<Color>>,
+    transparent_index: u8,
+}
+
+struct PlannedFrame {
+    area: Rectangle,
+    disposal_method: u8,
+    // Pixels that already show the right color are written as transparent.
+    unchanged: Vec<bool>,
+}
+
+fn opaque_pixels(frame: &RgbaImage, alpha_threshold: u8) -> Vec<Option<[u8; 3]>> {
+    frame
+        .pixels()
+        .map(|pixel| {
+            if pixel[3] < alpha_threshold {
+                None
+            } else {
+                Some([pixel[0], pixel[1], pixel[2]])
+            }
+        })
+        .collect()
+}
+
+fn unique_colors(pixels: &[Option<[u8; 3]>]) -> HashSet<[u8; 3]> {
+    pixels.iter().flatten().copied().collect()
+}
+
+fn to_colors(rgb: &[[u8; 3]]) -> Vec<Color> {
+    rgb.iter()
+        .map(|&[red, green, blue]| Color { red, green, blue })
+        .collect()
+}
+
+fn quantize(
+    pixels: &[Option<[u8; 3]>],
+    palette: &[[u8; 3]],
+    width: usize,
+    dither_options: &DitherOptions,
+    kept: &[Option<u8>],
+) -> QuantizedFrame {
+    let transparent_index = palette.len() as u8;
+    let mut index = PaletteIndex::from_rgb(palette);
+    let mapped = dither(pixels, width, palette, dither_options, kept, |rgb| {
+        index.nearest(rgb)
+    });
+
+    QuantizedFrame {
+        pixels: mapped
+            .iter()
+            .map(|index| index.map(|index| palette[index as usize]))
+            .collect(),
+        indices: mapped
+            .iter()
+            .map(|index| index.unwrap_or(transparent_index))
+            .collect(),
+        local_colors: None,
+        transparent_index,
+    }
+}
+
+pub fn bounding_box(width: usize, changed: &[bool]) -> Option<Rectangle> {
+    let mut left = usize::MAX;
+    let mut top = usize::MAX;
+    let mut right = 0;
+    let mut bottom = 0;
+    for (i, _) in changed.iter().enumerate().filter(|(_, &changed)| changed) {
+        let (x, y) = (i % width, i / width);
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x + 1);
+        bottom = bottom.max(y + 1);
+    }
+    if left == usize::MAX {
+        return None;
+    }
+    Some(Rectangle {
+        left,
+        top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+pub fn clear_area(pixels: &mut [Option<[u8; 3]>], width: usize, area: Rectangle) {
+    for y in area.top..area.top + area.height {
+        for pixel in &mut pixels[y * width + area.left..y * width + area.left + area.width] {
+            *pixel = None;
+        }
+    }
+}
+
+// True when the target needs a transparent pixel where the canvas still shows a color, which
+// no frame can draw.
+pub fn needs_clearing(canvas: &[Option<[u8; 3]>], target: &[Option<[u8; 3]>]) -> bool {
+    canvas
+        .iter()
+        .zip(target)
+        .any(|(canvas, target)| canvas.is_some() && target.is_none())
+}
+
+pub fn color_table_size(color_count: usize) -> u8 {
+    let mut size = 0;
+    while (2 << size) < color_count {
+        size += 1;
+    }
+    size
+}
+
+pub fn padded_colors(colors: &[Color], size: u8) -> Vec<Color> {
+    let mut colors = colors.to_vec();
+    colors.resize(
+        2 << size,
+        Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+        },
+    );
+    colors
+}
+
+// The previous frame's pixels before and after quantizing, so that pixels that did not change
+// can keep their color.
+struct PreviousFrame<'a> {
+    pixels: &'a [Option<[u8; 3]>],
+    quantized: &'a [Option<[u8; 3]>],
+}
+
+fn quantize_local(
+    pixels: &[Option<[u8; 3]>],
+    colors: HashSet<[u8; 3]>,
+    width: usize,
+    options: &EncodeOptions,
+    previous: Option<PreviousFrame>,
+) -> QuantizedFrame {
+    if colors.len() <= MAX_OPAQUE_COLORS {
+        let mut palette: Vec<[u8; 3]> = colors.into_iter().collect();
+        palette.sort();
+        let mut frame = quantize(pixels, &palette, width, &DitherOptions::default(), &[]);
+        frame.local_colors = Some(to_colors(&palette));
+        return frame;
+    }
+
+    let mut histogram = HashMap::new();
+    for &rgb in pixels.iter().flatten() {
+        *histogram.entry(rgb).or_insert(0) += 1;
+    }
+    let palette = options.quantizer.rgb_palette(&histogram, MAX_OPAQUE_COLORS);
+    let kept = kept_indices(pixels, previous, &palette, &options.dither);
+    let mut frame = quantize(pixels, &palette, width, &options.dither, &kept);
+    frame.local_colors = Some(to_colors(&palette));
+    frame
+}
+
+// The index each pixel had in the previous frame, for pixels that did not change and whose color
+// is still in the palette.
+fn kept_indices(
+    pixels: &[Option<[u8; 3]>],
+    previous: Option<PreviousFrame>,
+    palette: &[[u8; 3]],
+    dither_options: &DitherOptions,
+) -> Vec<Option<u8>> {
+    let Some(previous) = previous.filter(|_| dither_options.stable) else {
+        return Vec::new();
+    };
+    let positions: HashMap<[u8; 3], u8> = palette
+        .iter()
+        .enumerate()
+        .map(|(index, &rgb)| (rgb, index as u8))
+        .collect();
+    pixels
+        .iter()
+        .zip(previous.pixels)
+        .zip(previous.quantized)
+        .map(|((pixel, previous_pixel), previous_quantized)| {
+            if pixel != previous_pixel {
+                return None;
+            }
+            previous_quantized.and_then(|rgb| positions.get(&rgb).copied())
+        })
+        .collect()
+}
+
+fn build_image(
+    frame: &QuantizedFrame,
+    area: Rectangle,
+    indices: &[u8],
+    disposal_method: u8,
+    delay: u16,
+) -> GIFImage {
+    let color_count = frame.transparent_index as usize + 1;
+    let local_color_table = frame.local_colors.as_ref().map(|colors| LocalColorTable {
+        colors: padded_colors(colors, color_table_size(color_count)),
+    });
+
+    GIFImage {
+        extensions: vec![Extension::GraphicControl(GraphicControlExtension::new(
+            disposal_method,
+            delay,
+            Some(frame.transparent_index),
+        ))],
+        image_descriptor: ImageDescriptor {
+            separator: IMAGE_SEPARATOR,
+            left_position: area.left as u16,
+            top_position: area.top as u16,
+            width: area.width as u16,
+            height: area.height as u16,
+            packed_fields: IDPackedFields {
+                local_color_table_flag: local_color_table.is_some(),
+                interlace_flag: false,
+                sort_flag: false,
+                reserved: 0,
+                size_of_local_color_table: if local_color_table.is_some() {
+                    color_table_size(color_count)
+                } else {
+                    0
+                },
+            },
+        },
+        local_color_table,
+        image_data: ImageData::from_indices(ImageData::minimum_code_size(color_count), indices),
+    }
+}
+
+// Plans how `frame` is stored when drawn over the canvas the previous frame showed, and picks how
+// that frame is disposed of to make room for it. Without `optimize` every frame is stored whole
+// and clears itself.
+fn plan_frame(
+    frame: &QuantizedFrame,
+    previous: Option<(&QuantizedFrame, &mut PlannedFrame)>,
+    width: usize,
+    height: usize,
+    optimize: bool,
+) -> PlannedFrame {
+    let full_canvas = Rectangle {
+        left: 0,
+        top: 0,
+        width,
+        height,
+    };
+    let mut plan = PlannedFrame {
+        area: full_canvas,
+        disposal_method: DISPOSAL_NONE,
+        unchanged: vec![false; width * height],
+    };
+    let Some((previous_frame, previous)) = previous else {
+        return plan;
+    };
+    if !optimize {
+        previous.disposal_method = DISPOSAL_RESTORE_BACKGROUND;
+        return plan;
+    }
+
+    let mut canvas = previous_frame.pixels.clone();
+    if needs_clearing(&canvas, &frame.pixels) {
+        clear_area(&mut canvas, width, previous.area);
+        if needs_clearing(&canvas, &frame.pixels) {
+            // Clearing the previous frame's rectangle is not enough, so that frame is widened to
+            // the whole canvas and clears all of it.
+            previous.area = full_canvas;
+            previous.unchanged = vec![false; width * height];
+            canvas = vec![None; width * height];
+        }
+        previous.disposal_method = DISPOSAL_RESTORE_BACKGROUND;
+    }
+
+    plan.unchanged = canvas
+        .iter()
+        .zip(&frame.pixels)
+        .map(|(canvas, target)| canvas == target)
+        .collect();
+    let changed: Vec<bool> = plan.unchanged.iter().map(|unchanged| !unchanged).collect();
+    plan.area = bounding_box(width, &changed).unwrap_or(Rectangle {
+        left: 0,
+        top: 0,
+        width: 1,
+        height: 1,
+    });
+    plan
+}
+
+// The planned rectangle of the frame, with unchanged pixels written as transparent.
+fn frame_image(frame: &QuantizedFrame, plan: &PlannedFrame, width: usize, delay: u16) -> GIFImage {
+    let area = plan.area;
+    let mut indices = Vec::with_capacity(area.width * area.height);
+    for y in area.top..area.top + area.height {
+        for x in area.left..area.left + area.width {
+            let i = y * width + x;
+            indices.push(if plan.unchanged[i] {
+                frame.transparent_index
+            } else {
+                frame.indices[i]
+            });
+        }
+    }
+    build_image(frame, area, &indices, plan.disposal_method, delay)
+}
+
+// Turns full-canvas frames given one at a time into optimized gif frames, the way `from_frames`
+// does for a whole animation, while holding only the frame before. How a frame is disposed of
+// depends on the frame after it, so each frame is handed back once the next one comes in, or by
+// `finish`. Frames whose colors are all in the global palette draw from it, and the others get a
+// local table.
+pub struct StreamedFrames {
+    width: usize,
+    height: usize,
+    // The colors of the global color table, when it has an index to spare for transparency.
+    global_palette: Option<Vec<[u8; 3]>>,
+    options: EncodeOptions,
+    // The last frame's pixels before quantizing, and the frame itself with its plan and delay.
+    previous_pixels: Option<Vec<Option<[u8; 3]>>>,
+    pending: Option<(QuantizedFrame, PlannedFrame, u16)>,
+}
+
+impl StreamedFrames {
+    pub fn new(width: u16, height: u16, global_colors: Option<&[Color]>) -> StreamedFrames {
+        StreamedFrames {
+            width: width as usize,
+            height: height as usize,
+            global_palette: global_colors
+                .filter(|colors| colors.len() <= MAX_OPAQUE_COLORS)
+                .map(|colors| {
+                    colors
+                        .iter()
+                        .map(|color| [color.red, color.green, color.blue])
+                        .collect()
+                }),
+            options: EncodeOptions::default(),
+            previous_pixels: None,
+            pending: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Adds a frame of the canvas size, and hands back the frame before it, ready to be written.
+    pub fn push(&mut self, frame: &RgbaImage, delay: u16) -> Option<GIFImage> {
+        let pixels = opaque_pixels(frame, self.options.alpha_threshold);
+        let colors = unique_colors(&pixels);
+        let quantized = match &self.global_palette {
+            Some(palette) if colors.iter().all(|color| palette.contains(color)) => {
+                quantize(&pixels, palette, self.width, &DitherOptions::default(), &[])
+            }
+            _ => {
+                let previous = self.previous_pixels.as_ref().zip(self.pending.as_ref()).map(
+                    |(previous_pixels, (previous_frame, _, _))| PreviousFrame {
+                        pixels: previous_pixels,
+                        quantized: &previous_frame.pixels,
+                    },
+                );
+                quantize_local(&pixels, colors, self.width, &self.options, previous)
+            }
+        };
+
+        let mut pending = self.pending.take();
+        let previous = pending
+            .as_mut()
+            .map(|(previous_frame, previous_plan, _)| (&*previous_frame, previous_plan));
+        let plan = plan_frame(
+            &quantized,
+            previous,
+            self.width,
+            self.height,
+            self.options.optimize,
+        );
+        self.pending = Some((quantized, plan, delay));
+        self.previous_pixels = Some(pixels);
+        pending.map(|(frame, plan, delay)| frame_image(&frame, &plan, self.width, delay))
+    }
+
+    // Hands back the last frame. What it leaves on the canvas is forgotten, so the next frame
+    // pushed is stored whole.
+    pub fn finish(&mut self) -> Option<GIFImage> {
+        self.previous_pixels = None;
+        self.pending
+            .take()
+            .map(|(frame, plan, delay)| frame_image(&frame, &plan, self.width, delay))
+    }
+}
+
+// Encodes a single image as a frame with its own local color table, placed at the given offset.
+// Transparent pixels leave the canvas underneath untouched.
+pub fn local_frame(
+    image: &RgbaImage,
+    left: u16,
+    top: u16,
+    delay: u16,
+    disposal_method: u8,
+    options: &EncodeOptions,
+) -> GIFImage {
+    let pixels = opaque_pixels(image, options.alpha_threshold);
+    let colors = unique_colors(&pixels);
+    let frame = quantize_local(&pixels, colors, image.width() as usize, options, None);
+    let area = Rectangle {
+        left: left as usize,
+        top: top as usize,
+        width: image.width() as usize,
+        height: image.height() as usize,
+    };
+    build_image(&frame, area, &frame.indices, disposal_method, delay)
+}
+
+impl GIF {
+    // Builds a GIF from full-canvas frames, with delays in hundredths of a second. Frames share
+    // one global color table when all of their colors fit in it, and otherwise each gets an exact
+    // local table when its own colors fit in one. Only when some frame has too many colors does
+    // the options' quantizer reduce them, to one palette over every frame when dithering is
+    // stable, or to a local table per frame when it is not.
+    pub fn from_frames(
+        frames: &[RgbaImage],
+        delays: &[u16],
+        options: &EncodeOptions,
+    ) -> Result<GIF, GifError> {
+        let first_frame = frames.first().ok_or(GifError::NoFrames)?;
+        if frames.len() != delays.len() {
+            return Err(GifError::InvalidInput {
+                message: format!("Got {} frames but {} delays", frames.len(), delays.len()),
+            });
+        }
+        let (width, height) = first_frame.dimensions();
+        if width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(GifError::InvalidInput {
+                message: format!("Frame size {}x{} is too large for a gif", width, height),
+            });
+        }
+        if let Some(frame) = frames
+            .iter()
+            .find(|frame| frame.dimensions() != (width, height))
+        {
+            return Err(GifError::InvalidInput {
+                message: format!(
+                    "Frame size {}x{} differs from the first frame size {}x{}",
+                    frame.width(),
+                    frame.height(),
+                    width,
+                    height
+                ),
+            });
+        }
+        let width = width as usize;
+        let height = height as usize;
+
+        let pixels: Vec<Vec<Option<[u8; 3]>>> = frames
+            .iter()
+            .map(|frame| opaque_pixels(frame, options.alpha_threshold))
+            .collect();
+        let frame_colors: Vec<HashSet<[u8; 3]>> =
+            pixels.iter().map(|pixels| unique_colors(pixels)).collect();
+        let mut all_colors: HashSet<[u8; 3]> = HashSet::new();
+        for colors in &frame_colors {
+            all_colors.extend(colors);
+        }
+
+        let mut global_colors = None;
+        let quantized: Vec<QuantizedFrame> = if all_colors.len() <= MAX_OPAQUE_COLORS {
+            let mut palette: Vec<[u8; 3]> = all_colors.into_iter().collect();
+            palette.sort();
+            global_colors = Some(to_colors(&palette));
+            pixels
+                .iter()
+                .map(|pixels| quantize(pixels, &palette, width, &DitherOptions::default(), &[]))
+                .collect()
+        } else if options.dither.stable
+            && frame_colors
+                .iter()
+                .any(|colors| colors.len() > MAX_OPAQUE_COLORS)
+        {
+            // One palette for every frame, so pixels that do not change keep their color.
+            let palette = options.quantizer.rgb_palette(
+                &histogram(frames, options.alpha_threshold),
+                MAX_OPAQUE_COLORS,
+            );
+            global_colors = Some(to_colors(&palette));
+            let mut quantized: Vec<QuantizedFrame> = Vec::with_capacity(pixels.len());
+            for i in 0..pixels.len() {
+                let previous = i.checked_sub(1).map(|previous| PreviousFrame {
+                    pixels: &pixels[previous],
+                    quantized: &quantized[previous].pixels,
+                });
+                let kept = kept_indices(&pixels[i], previous, &palette, &options.dither);
+                let frame = quantize(&pixels[i], &palette, width, &options.dither, &kept);
+                quantized.push(frame);
+            }
+            quantized
+        } else {
+            let mut quantized: Vec<QuantizedFrame> = Vec::with_capacity(pixels.len());
+            for (i, colors) in frame_colors.into_iter().enumerate() {
+                let previous = i.checked_sub(1).map(|previous| PreviousFrame {
+                    pixels: &pixels[previous],
+                    quantized: &quantized[previous].pixels,
+                });
+                let frame = quantize_local(&pixels[i], colors, width, options, previous);
+                quantized.push(frame);
+            }
+            quantized
+        };
+
+        let mut plans: Vec<PlannedFrame> = Vec::with_capacity(quantized.len());
+        for (i, frame) in quantized.iter().enumerate() {
+            let previous = i
+                .checked_sub(1)
+                .map(|previous| (&quantized[previous], plans.last_mut().unwrap()));
+            let plan = plan_frame(frame, previous, width, height, options.optimize);
+            plans.push(plan);
+        }
+
+        let images: Vec<GIFImage> = quantized
+            .iter()
+            .zip(&plans)
+            .zip(delays)
+            .map(|((frame, plan), &delay)| frame_image(frame, plan, width, delay))
+            .collect();
+
+        let global_color_table = global_colors.map(|colors| {
+            let size = color_table_size(colors.len() + 1);
+            GlobalColorTable {
+                colors: padded_colors(&colors, size),
+            }
+        });
+
+        let gif = GIF {
+            header: GIFHeader {
+                signature: GIF_SIGNATURE,
+                version: GIF_89A_VERSION,
+            },
+            logical_screen_descriptor: LogicalScreenDescriptor {
+                width: width as u16,
+                height: height as u16,
+                packed_fields: LSDPackedFields {
+                    global_color_table_flag: global_color_table.is_some(),
+                    color_resolution: 7,
+                    sort_flag: false,
+                    size_of_global_color_table: global_color_table
+                        .as_ref()
+                        .map(|table| color_table_size(table.colors.len()))
+                        .unwrap_or(0),
+                },
+                background_color_index: 0,
+                pixel_aspect_ratio: 0,
+            },
+            global_color_table,
+            extensions: Vec::new(),
+            images,
+            trailing_extensions: Vec::new(),
+            trailer: Trailer { trailer: 0x3B },
+        };
+        if options.lossy > 0 {
+            return gif.compress_lossy(options.lossy);
+        }
+        Ok(gif)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn frames_with_their_own_palettes_keep_every_color() {
+        // 200 reds, then 200 blues: too many for one table, but each frame fits its own.
+        let reds = RgbaImage::from_fn(20, 10, |x, y| Rgba([(y * 20 + x + 40) as u8, 0, 0, 255]));
+        let blues = RgbaImage::from_fn(20, 10, |x, y| Rgba([0, 0, (y * 20 + x + 40) as u8, 255]));
+        let frames = [reds, blues];
+
+        let gif = GIF::from_frames(&frames, &[10, 10], &EncodeOptions::default()).unwrap();
+        assert!(gif.global_color_table.is_none());
+        assert!(gif.frames().unwrap() == frames);
+    }
+}
@@ -0,0 +1,328 @@
+use image::{imageops, Rgba, RgbaImage};
+use rusttype::Font;
+
+use super::builder::local_frame;
+use super::compositor::{DISPOSAL_RESTORE_BACKGROUND, DISPOSAL_RESTORE_PREVIOUS};
+use super::{Compositor, EncodeOptions, GifError, GIF};
+use crate::layout::{fit_font_size, layout_text, Alignment, LayoutOptions};
+use crate::text_to_image::{outline, render_layout, TextOptions};
+
+const DEFAULT_FONT: &[u8] = include_bytes!("../../res/fonts/Arial.ttf");
+
+#[derive(Clone, Copy)]
+pub enum CaptionPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
+pub struct CaptionStyle {
+    pub font: Font<'static>,
+    // The largest font size used. Captions are wrapped to the width of the gif, and the size is
+    // lowered when the text still does not fit.
+    pub font_size: f32,
+    pub color: Rgba<u8>,
+    pub position: CaptionPosition,
+    // Distance in pixels between the text and the top or bottom edge of the canvas, or the edges
+    // of the strip in caption bar mode.
+    pub margin: u32,
+    // Width in pixels of the outline drawn around the text, 0 for none.
+    pub stroke_width: u32,
+    pub stroke_color: Rgba<u8>,
+    pub alignment: Alignment,
+    pub line_spacing: f32,
+}
+
+impl Default for CaptionStyle {
+    fn default() -> CaptionStyle {
+        CaptionStyle {
+            font: Font::try_from_bytes(DEFAULT_FONT).expect("Bundled font is valid"),
+            font_size: 32.0,
+            color: Rgba([255, 255, 255, 255]),
+            position: CaptionPosition::Bottom,
+            margin: 8,
+            // White text alone disappears on light frames.
+            stroke_width: 2,
+            stroke_color: Rgba([0, 0, 0, 255]),
+            alignment: Alignment::Center,
+            line_spacing: 1.0,
+        }
+    }
+}
+
+// The strip added by caption bar mode and the text written on it. By default black text on a
+// white strip.
+pub struct CaptionBarStyle {
+    pub text: CaptionStyle,
+    pub bar_color: Rgba<u8>,
+}
+
+impl Default for CaptionBarStyle {
+    fn default() -> CaptionBarStyle {
+        CaptionBarStyle {
+            text: CaptionStyle::bar(),
+            bar_color: Rgba([255, 255, 255, 255]),
+        }
+    }
+}
+
+impl CaptionStyle {
+    // Plain black text, as written on the white strip of a caption bar.
+    pub fn bar() -> CaptionStyle {
+        CaptionStyle {
+            color: Rgba([0, 0, 0, 255]),
+            stroke_width: 0,
+            ..CaptionStyle::default()
+        }
+    }
+
+    // White text with a black outline, the classic top and bottom meme text.
+    pub fn meme() -> CaptionStyle {
+        CaptionStyle {
+            font_size: 48.0,
+            stroke_width: 3,
+            ..CaptionStyle::default()
+        }
+    }
+
+    // Renders the text wrapped to the box, at the largest font size that fits it.
+    fn render(&self, text: &str, box_width: u32, box_height: f32) -> RgbaImage {
+        let box_width = box_width.saturating_sub(2 * (self.margin + self.stroke_width)) as f32;
+        let options = LayoutOptions {
+            max_width: Some(box_width),
+            alignment: self.alignment,
+            line_spacing: self.line_spacing,
+        };
+        let font_size = fit_font_size(
+            &self.font,
+            text,
+            self.font_size,
+            box_width,
+            box_height,
+            &options,
+        );
+        let layout = layout_text(&self.font, text, font_size, &options);
+        outline(
+            &render_layout(
+                &layout,
+                &TextOptions {
+                    color: self.color,
+                    background: None,
+                    padding: None,
+                },
+            )
+            .image,
+            self.stroke_width,
+            self.stroke_color,
+        )
+    }
+}
+
+impl GIF {
+    // Burns the text into every frame. Frames are composited first, so partial frames,
+    // transparency and local color tables are all handled, and the result is re-encoded.
+    pub fn caption(&self, text: &str, style: &CaptionStyle) -> Result<GIF, GifError> {
+        self.caption_at(&[(text, style.position)], style)
+    }
+
+    // Draws top and bottom text over the animation, either of which may be left out.
+    pub fn meme(
+        &self,
+        top_text: Option<&str>,
+        bottom_text: Option<&str>,
+        style: &CaptionStyle,
+    ) -> Result<GIF, GifError> {
+        let mut captions = Vec::new();
+        if let Some(top_text) = top_text {
+            captions.push((top_text, CaptionPosition::Top));
+        }
+        if let Some(bottom_text) = bottom_text {
+            captions.push((bottom_text, CaptionPosition::Bottom));
+        }
+        self.caption_at(&captions, style)
+    }
+
+    fn caption_at(
+        &self,
+        captions: &[(&str, CaptionPosition)],
+        style: &CaptionStyle,
+    ) -> Result<GIF, GifError> {
+        let width = self.logical_screen_descriptor.width as i64;
+        let height = self.logical_screen_descriptor.height as i64;
+
+        let mut placed = Vec::with_capacity(captions.len());
+        for &(text, position) in captions {
+            // Each caption may take up to a third of the canvas height.
+            let text_image = style.render(
+                text,
+                width as u32,
+                height as f32 / 3.0 - style.margin as f32,
+            );
+            let x = (width - text_image.width() as i64) / 2;
+            let y = match position {
+                CaptionPosition::Top => style.margin as i64,
+                CaptionPosition::Center => (height - text_image.height() as i64) / 2,
+                CaptionPosition::Bottom => {
+                    height - text_image.height() as i64 - style.margin as i64
+                }
+            };
+            placed.push((text_image, x, y));
+        }
+
+        let mut frames = self.frames()?;
+        for frame in &mut frames {
+            for (text_image, x, y) in &placed {
+                imageops::overlay(frame, text_image, *x, *y);
+            }
+        }
+
+        let captioned = GIF::from_frames(&frames, &self.delays(), &EncodeOptions::default())?;
+        Ok(captioned.with_file_extensions_of(self))
+    }
+
+    // Grows the canvas by a strip above the animation and writes the text on it. Only the first
+    // frame is re-encoded, to also draw the strip; every other frame is copied through and moved
+    // down. When the first frame is disposed of after it is shown, the strip would be erased with
+    // it, so the frames are composited and re-encoded instead.
+    pub fn caption_bar(&self, text: &str, bar_style: &CaptionBarStyle) -> Result<GIF, GifError> {
+        let style = &bar_style.text;
+        let text_image = style.render(
+            text,
+            self.logical_screen_descriptor.width as u32,
+            f32::INFINITY,
+        );
+
+        let width = self.logical_screen_descriptor.width;
+        let height = self.logical_screen_descriptor.height;
+        let bar_height = text_image.height() + 2 * style.margin;
+        let new_height = height as u32 + bar_height;
+        if new_height > u16::MAX as u32 {
+            return Err(GifError::InvalidInput {
+                message: format!("Caption bar makes the gif {} pixels high", new_height),
+            });
+        }
+
+        let mut bar = RgbaImage::from_pixel(width as u32, bar_height, bar_style.bar_color);
+        imageops::overlay(
+            &mut bar,
+            &text_image,
+            (width as i64 - text_image.width() as i64) / 2,
+            style.margin as i64,
+        );
+
+        let first_image = self.images.first().ok_or(GifError::NoFrames)?;
+        let first_disposal_method = first_image
+            .graphic_control_extension()
+            .map(|graphic_control_extension| {
+                graphic_control_extension.packed_fields.disposal_method
+            })
+            .unwrap_or(0);
+
+        if first_disposal_method == DISPOSAL_RESTORE_BACKGROUND
+            || first_disposal_method == DISPOSAL_RESTORE_PREVIOUS
+        {
+            let frames: Vec<RgbaImage> = self
+                .frames()?
+                .iter()
+                .map(|frame| {
+                    let mut captioned = RgbaImage::new(width as u32, new_height);
+                    imageops::replace(&mut captioned, &bar, 0, 0);
+                    imageops::replace(&mut captioned, frame, 0, bar_height as i64);
+                    captioned
+                })
+                .collect();
+            let captioned = GIF::from_frames(&frames, &self.delays(), &EncodeOptions::default())?;
+            return Ok(captioned.with_file_extensions_of(self));
+        }
+
+        let mut captioned = self.clone();
+        captioned.logical_screen_descriptor.height = new_height as u16;
+        for image in &mut captioned.images {
+            let descriptor = &mut image.image_descriptor;
+            descriptor.top_position = descriptor
+                .top_position
+                .checked_add(bar_height as u16)
+                .ok_or_else(|| GifError::InvalidInput {
+                    message: format!(
+                        "A frame at row {} cannot move down by the {} pixel caption bar",
+                        descriptor.top_position, bar_height
+                    ),
+                })?;
+        }
+
+        // The strip and the first frame are drawn together, so the strip shows from the start
+        // and is redrawn on every loop.
+        let descriptor = &first_image.image_descriptor;
+        let first_frame =
+            Compositor::new(width, height).draw(first_image, self.global_color_table.as_ref())?;
+        let merged_height = bar_height
+            + (descriptor.top_position as u32 + descriptor.height as u32).min(height as u32);
+        let mut merged = RgbaImage::new(width as u32, merged_height);
+        imageops::replace(&mut merged, &bar, 0, 0);
+        imageops::replace(&mut merged, &first_frame, 0, bar_height as i64);
+
+        let merged_image = local_frame(
+            &merged,
+            0,
+            0,
+            first_image.delay(),
+            first_disposal_method,
+            &EncodeOptions::default(),
+        );
+
+        let first = &mut captioned.images[0];
+        if let Some(merged_graphic_control_extension) = merged_image.graphic_control_extension() {
+            match first.graphic_control_extension_mut() {
+                Some(graphic_control_extension) => graphic_control_extension
+                    .set_transparent_index(merged_graphic_control_extension.transparent_index()),
+                None => {
+                    first.set_graphic_control_extension(merged_graphic_control_extension.clone())
+                }
+            }
+        }
+        first.image_descriptor = merged_image.image_descriptor;
+        first.local_color_table = merged_image.local_color_table;
+        first.image_data = merged_image.image_data;
+
+        Ok(captioned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caption_bar_writes_black_text_on_a_white_strip() {
+        let frame = RgbaImage::from_pixel(80, 40, Rgba([40, 120, 200, 255]));
+        let gif = GIF::from_frames(&[frame], &[10], &EncodeOptions::default()).unwrap();
+        let captioned = gif.caption_bar("I", &CaptionBarStyle::default()).unwrap();
+
+        let bar_height = captioned.logical_screen_descriptor.height as u32 - 40;
+        let first = &captioned.frames().unwrap()[0];
+        assert_eq!(*first.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+
+        // Across the middle of the strip the letter is a single dark run, with no light inside
+        // it as outlined white text would have.
+        let row: Vec<bool> = (0..80)
+            .map(|x| first.get_pixel(x, bar_height / 2)[0] < 128)
+            .collect();
+        let dark_runs = row
+            .windows(2)
+            .filter(|pair| !pair[0] && pair[1])
+            .count();
+        assert_eq!(dark_runs, 1);
+    }
+
+    #[test]
+    fn caption_bar_rejects_frames_it_cannot_move_down() {
+        let frame = RgbaImage::from_pixel(80, 40, Rgba([40, 120, 200, 255]));
+        let frames = [frame.clone(), frame];
+        let mut gif = GIF::from_frames(&frames, &[10, 10], &EncodeOptions::default()).unwrap();
+        gif.images[1].image_descriptor.top_position = 65530;
+        assert!(matches!(
+            gif.caption_bar("Caption", &CaptionBarStyle::default()),
+            Err(GifError::InvalidInput { .. })
+        ));
+    }
+}
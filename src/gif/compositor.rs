@@ -0,0 +1,236 @@
+use image::{Rgba, RgbaImage};
+
+use super::{Color, GIFImage, GifError, GlobalColorTable};
+
+const CLEAR: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+pub const DISPOSAL_UNSPECIFIED: u8 = 0;
+pub const DISPOSAL_NONE: u8 = 1;
+pub const DISPOSAL_RESTORE_BACKGROUND: u8 = 2;
+pub const DISPOSAL_RESTORE_PREVIOUS: u8 = 3;
+
+#[derive(Clone, Copy, Debug)]
+struct Rectangle {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+// Replays frames onto a logical screen the way browsers do, so each drawn frame is the full
+// canvas a viewer would see at that point of the animation. Restoring to the background clears
+// to transparent, which is what every browser does instead of using the background color.
+pub struct Compositor {
+    canvas: RgbaImage,
+    disposal_method: u8,
+    disposal_area: Option<Rectangle>,
+    saved_canvas: Option<RgbaImage>,
+}
+
+impl Compositor {
+    pub fn new(width: u16, height: u16) -> Compositor {
+        Compositor {
+            canvas: RgbaImage::from_pixel(width as u32, height as u32, CLEAR),
+            disposal_method: DISPOSAL_UNSPECIFIED,
+            disposal_area: None,
+            saved_canvas: None,
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        image: &GIFImage,
+        global_color_table: Option<&GlobalColorTable>,
+    ) -> Result<RgbaImage, GifError> {
+        self.dispose();
+
+        let colors = image.color_table(global_color_table)?;
+        let transparent_index = image.transparent_index();
+        let disposal_method = image
+            .graphic_control_extension()
+            .map_or(DISPOSAL_UNSPECIFIED, |graphic_control_extension| {
+                graphic_control_extension.packed_fields.disposal_method
+            });
+
+        if disposal_method == DISPOSAL_RESTORE_PREVIOUS {
+            self.saved_canvas = Some(self.canvas.clone());
+        }
+
+        let descriptor = &image.image_descriptor;
+        let area = Rectangle {
+            left: descriptor.left_position as u32,
+            top: descriptor.top_position as u32,
+            width: descriptor.width as u32,
+            height: descriptor.height as u32,
+        };
+        let indices = image.indices()?;
+
+        for y in 0..area.height {
+            let canvas_y = area.top + y;
+            if canvas_y >= self.canvas.height() {
+                break;
+            }
+            for x in 0..area.width {
+                let canvas_x = area.left + x;
+                if canvas_x >= self.canvas.width() {
+                    break;
+                }
+                let index = indices[(y * area.width + x) as usize];
+                if Some(index) == transparent_index {
+                    continue;
+                }
+                // Indices past the end of the color table are left undrawn, like browsers do.
+                if let Some(color) = colors.get(index as usize) {
+                    self.canvas.put_pixel(
+                        canvas_x,
+                        canvas_y,
+                        Rgba([color.red, color.green, color.blue, 255]),
+                    );
+                }
+            }
+        }
+
+        self.disposal_method = disposal_method;
+        self.disposal_area = Some(area);
+
+        Ok(self.canvas.clone())
+    }
+
+    fn dispose(&mut self) {
+        match self.disposal_method {
+            DISPOSAL_RESTORE_BACKGROUND => {
+                if let Some(area) = self.disposal_area {
+                    let right = (area.left + area.width).min(self.canvas.width());
+                    let bottom = (area.top + area.height).min(self.canvas.height());
+                    for y in area.top..bottom {
+                        for x in area.left..right {
+                            self.canvas.put_pixel(x, y, CLEAR);
+                        }
+                    }
+                }
+            }
+            DISPOSAL_RESTORE_PREVIOUS => {
+                if let Some(saved_canvas) = self.saved_canvas.take() {
+                    self.canvas = saved_canvas;
+                }
+            }
+            _ => {}
+        }
+        self.disposal_method = DISPOSAL_UNSPECIFIED;
+        self.disposal_area = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        Extension, GraphicControlExtension, IDPackedFields, ImageData, ImageDescriptor,
+        IMAGE_SEPARATOR,
+    };
+    use super::*;
+
+    const RED: u8 = 0;
+    const GREEN: u8 = 1;
+    const BLUE: u8 = 2;
+    const SEE_THROUGH: u8 = 3;
+
+    fn global_color_table() -> GlobalColorTable {
+        GlobalColorTable {
+            colors: [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]]
+                .iter()
+                .map(|&[red, green, blue]| Color { red, green, blue })
+                .collect(),
+        }
+    }
+
+    // A frame drawn from the global table, with index 3 left transparent.
+    fn frame(left: u16, top: u16, width: u16, indices: &[u8], disposal_method: u8) -> GIFImage {
+        GIFImage {
+            extensions: vec![Extension::GraphicControl(GraphicControlExtension::new(
+                disposal_method,
+                10,
+                Some(SEE_THROUGH),
+            ))],
+            image_descriptor: ImageDescriptor {
+                separator: IMAGE_SEPARATOR,
+                left_position: left,
+                top_position: top,
+                width,
+                height: indices.len() as u16 / width,
+                packed_fields: IDPackedFields {
+                    local_color_table_flag: false,
+                    interlace_flag: false,
+                    sort_flag: false,
+                    reserved: 0,
+                    size_of_local_color_table: 0,
+                },
+            },
+            local_color_table: None,
+            image_data: ImageData::from_indices(2, indices),
+        }
+    }
+
+    // Draws every frame on a 4x4 canvas and returns the canvases.
+    fn composite(frames: &[GIFImage]) -> Vec<RgbaImage> {
+        let global_color_table = global_color_table();
+        let mut compositor = Compositor::new(4, 4);
+        frames
+            .iter()
+            .map(|frame| compositor.draw(frame, Some(&global_color_table)).unwrap())
+            .collect()
+    }
+
+    // One character per pixel: R, G and B for the colors, . for transparent.
+    fn canvas(rows: [&str; 4]) -> RgbaImage {
+        RgbaImage::from_fn(4, 4, |x, y| match rows[y as usize].as_bytes()[x as usize] {
+            b'R' => Rgba([255, 0, 0, 255]),
+            b'G' => Rgba([0, 255, 0, 255]),
+            b'B' => Rgba([0, 0, 255, 255]),
+            _ => CLEAR,
+        })
+    }
+
+    #[test]
+    fn restore_to_background_clears_the_frame_rectangle() {
+        let canvases = composite(&[
+            frame(0, 0, 4, &[RED; 16], DISPOSAL_NONE),
+            frame(1, 1, 2, &[GREEN; 4], DISPOSAL_RESTORE_BACKGROUND),
+            frame(0, 0, 1, &[BLUE], DISPOSAL_NONE),
+        ]);
+        assert!(canvases[1] == canvas(["RRRR", "RGGR", "RGGR", "RRRR"]));
+        assert!(canvases[2] == canvas(["BRRR", "R..R", "R..R", "RRRR"]));
+    }
+
+    #[test]
+    fn restore_to_previous_brings_back_the_canvas_before_the_frame() {
+        let canvases = composite(&[
+            frame(0, 0, 4, &[RED; 16], DISPOSAL_NONE),
+            frame(0, 0, 2, &[GREEN; 4], DISPOSAL_RESTORE_PREVIOUS),
+            frame(3, 3, 1, &[BLUE], DISPOSAL_NONE),
+        ]);
+        assert!(canvases[1] == canvas(["GGRR", "GGRR", "RRRR", "RRRR"]));
+        assert!(canvases[2] == canvas(["RRRR", "RRRR", "RRRR", "RRRB"]));
+    }
+
+    #[test]
+    fn transparent_pixels_leave_the_canvas_underneath() {
+        let mut see_through = [SEE_THROUGH; 16];
+        see_through[1] = GREEN;
+        see_through[15] = BLUE;
+        let canvases = composite(&[
+            frame(0, 0, 4, &[RED; 16], DISPOSAL_NONE),
+            frame(0, 0, 4, &see_through, DISPOSAL_NONE),
+        ]);
+        assert!(canvases[1] == canvas(["RGRR", "RRRR", "RRRR", "RRRB"]));
+    }
+
+    #[test]
+    fn frames_past_the_canvas_edge_are_cut_off() {
+        let canvases = composite(&[
+            frame(2, 2, 4, &[GREEN; 16], DISPOSAL_RESTORE_BACKGROUND),
+            frame(0, 0, 1, &[BLUE], DISPOSAL_NONE),
+        ]);
+        assert!(canvases[0] == canvas(["....", "....", "..GG", "..GG"]));
+        assert!(canvases[1] == canvas(["B...", "....", "....", "...."]));
+    }
+}
@@ -0,0 +1,540 @@
+use std::io::{self, BufRead, Read};
+
+use image::RgbaImage;
+
+use super::looping::loop_count_of;
+use super::{
+    ApplicationExtension, BlockKind, Color, CommentExtension, Compositor, Extension,
+    GCEPackedFields, GIFDataSubBlock, GIFHeader, GIFImage, GifError, GlobalColorTable,
+    GraphicControlExtension, IDPackedFields, ImageData, ImageDescriptor, LSDPackedFields,
+    LocalColorTable, LogicalScreenDescriptor, LoopCount, PlainTextExtension, Trailer,
+    UnknownExtension, APPLICATION_BLOCK_SIZE, APPLICATION_LABEL, COMMENT_LABEL,
+    EXTENSION_INTRODUCER, GIF, GIF_SIGNATURE, GRAPHIC_CONTROL_BLOCK_SIZE, GRAPHIC_CONTROL_LABEL,
+    IMAGE_SEPARATOR, PLAIN_TEXT_BLOCK_SIZE, PLAIN_TEXT_LABEL, TRAILER,
+};
+
+// Everything before the first block: the header, the logical screen descriptor and the global
+// color table.
+pub struct Screen {
+    pub header: GIFHeader,
+    pub logical_screen_descriptor: LogicalScreenDescriptor,
+    pub global_color_table: Option<GlobalColorTable>,
+}
+
+pub enum Block {
+    Extension(Extension),
+    // An image with an empty extension list, the extensions before it come as their own blocks.
+    Image(GIFImage),
+    Trailer(Trailer),
+}
+
+// Reads a gif block by block. Fixed size fields, color tables and data sub-blocks are each read
+// with a single call, and the number of bytes read is kept, so errors can say where the data is
+// broken.
+pub struct Parser<R: Read> {
+    reader: R,
+    offset: u64,
+    images: usize,
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(reader: R) -> Parser<R> {
+        Parser {
+            reader,
+            offset: 0,
+            images: 0,
+        }
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8], block: BlockKind) -> Result<(), GifError> {
+        match self.reader.read_exact(buffer) {
+            Ok(()) => {
+                self.offset += buffer.len() as u64;
+                Ok(())
+            }
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(GifError::UnexpectedEof {
+                    offset: self.offset,
+                    block,
+                })
+            }
+            Err(error) => Err(GifError::Io(error)),
+        }
+    }
+
+    fn read_bytes<const N: usize>(&mut self, block: BlockKind) -> Result<[u8; N], GifError> {
+        let mut bytes = [0u8; N];
+        self.read_exact(&mut bytes, block)?;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self, block: BlockKind) -> Result<u8, GifError> {
+        Ok(self.read_bytes::<1>(block)?[0])
+    }
+
+    // A color table of 2^(size + 1) colors, as given by the size field of its packed fields.
+    fn read_colors(&mut self, size: u8, block: BlockKind) -> Result<Vec<Color>, GifError> {
+        let mut bytes = vec![0u8; 3 << (size + 1)];
+        self.read_exact(&mut bytes, block)?;
+        Ok(bytes
+            .chunks_exact(3)
+            .map(|color| Color {
+                red: color[0],
+                green: color[1],
+                blue: color[2],
+            })
+            .collect())
+    }
+
+    // The block size byte of an extension and the block it gives the size of. The first N bytes
+    // hold the fields, and bytes past them, which some encoders write, are skipped. A block too
+    // short for the fields is refused rather than read into what follows it.
+    fn read_sized_block<const N: usize>(
+        &mut self,
+        block: BlockKind,
+    ) -> Result<(u8, [u8; N]), GifError> {
+        let offset = self.offset;
+        let block_size = self.read_u8(block)?;
+        if (block_size as usize) < N {
+            return Err(GifError::InvalidStructure {
+                block,
+                message: format!(
+                    "block size {} at byte {} is smaller than the {} bytes of its fields",
+                    block_size, offset, N
+                ),
+            });
+        }
+        let mut bytes = vec![0u8; block_size as usize];
+        self.read_exact(&mut bytes, block)?;
+        let mut fields = [0u8; N];
+        fields.copy_from_slice(&bytes[..N]);
+        Ok((block_size, fields))
+    }
+
+    // Data sub-blocks up to and including the 0 size block that ends them.
+    fn read_sub_blocks(&mut self, block: BlockKind) -> Result<Vec<GIFDataSubBlock>, GifError> {
+        let mut sub_blocks = Vec::new();
+        loop {
+            let size = self.read_u8(block)?;
+            if size == 0 {
+                return Ok(sub_blocks);
+            }
+            let mut data = vec![0u8; size as usize];
+            self.read_exact(&mut data, block)?;
+            sub_blocks.push(GIFDataSubBlock { size, data });
+        }
+    }
+
+    pub fn read_screen(&mut self) -> Result<Screen, GifError> {
+        let header_bytes = self.read_bytes::<6>(BlockKind::Header)?;
+        if header_bytes[0..3] != GIF_SIGNATURE {
+            return Err(GifError::BadSignature { offset: 0 });
+        }
+        let header = GIFHeader {
+            signature: [header_bytes[0], header_bytes[1], header_bytes[2]],
+            version: [header_bytes[3], header_bytes[4], header_bytes[5]],
+        };
+
+        let bytes = self.read_bytes::<7>(BlockKind::LogicalScreenDescriptor)?;
+        let logical_screen_descriptor = LogicalScreenDescriptor {
+            width: u16::from_le_bytes([bytes[0], bytes[1]]),
+            height: u16::from_le_bytes([bytes[2], bytes[3]]),
+            packed_fields: LSDPackedFields::from_byte(bytes[4]),
+            background_color_index: bytes[5],
+            pixel_aspect_ratio: bytes[6],
+        };
+
+        let packed_fields = &logical_screen_descriptor.packed_fields;
+        let global_color_table = if packed_fields.global_color_table_flag {
+            let colors = self.read_colors(
+                packed_fields.size_of_global_color_table,
+                BlockKind::GlobalColorTable,
+            )?;
+            Some(GlobalColorTable { colors })
+        } else {
+            None
+        };
+
+        Ok(Screen {
+            header,
+            logical_screen_descriptor,
+            global_color_table,
+        })
+    }
+
+    // Files that end right after a block, without a trailer, are common enough that browsers
+    // show them, so once a frame has been read a clean end of file counts as the trailer.
+    pub fn read_block(&mut self) -> Result<Block, GifError> {
+        let introducer = match self.read_u8(BlockKind::BlockIntroducer) {
+            Err(GifError::UnexpectedEof { .. }) if self.images > 0 => {
+                return Ok(Block::Trailer(Trailer { trailer: TRAILER }))
+            }
+            introducer => introducer?,
+        };
+        match introducer {
+            IMAGE_SEPARATOR => Ok(Block::Image(self.read_image()?)),
+            EXTENSION_INTRODUCER => Ok(Block::Extension(self.read_extension()?)),
+            TRAILER => Ok(Block::Trailer(Trailer { trailer: TRAILER })),
+            _ => Err(GifError::UnknownBlock {
+                offset: self.offset - 1,
+                introducer,
+            }),
+        }
+    }
+
+    fn read_image(&mut self) -> Result<GIFImage, GifError> {
+        let bytes = self.read_bytes::<9>(BlockKind::ImageDescriptor)?;
+        let image_descriptor = ImageDescriptor {
+            separator: IMAGE_SEPARATOR,
+            left_position: u16::from_le_bytes([bytes[0], bytes[1]]),
+            top_position: u16::from_le_bytes([bytes[2], bytes[3]]),
+            width: u16::from_le_bytes([bytes[4], bytes[5]]),
+            height: u16::from_le_bytes([bytes[6], bytes[7]]),
+            packed_fields: IDPackedFields::from_byte(bytes[8]),
+        };
+
+        let packed_fields = &image_descriptor.packed_fields;
+        let local_color_table = if packed_fields.local_color_table_flag {
+            let colors = self.read_colors(
+                packed_fields.size_of_local_color_table,
+                BlockKind::LocalColorTable,
+            )?;
+            Some(LocalColorTable { colors })
+        } else {
+            None
+        };
+
+        let offset = self.offset;
+        let lzw_minimum_code_size = self.read_u8(BlockKind::ImageData)?;
+        let sub_blocks = self.read_sub_blocks(BlockKind::ImageData)?;
+        let frame = self.images;
+        self.images += 1;
+
+        Ok(GIFImage {
+            extensions: Vec::new(),
+            image_descriptor,
+            local_color_table,
+            image_data: ImageData {
+                lzw_minimum_code_size,
+                sub_blocks,
+                frame: Some(frame),
+                offset: Some(offset),
+            },
+        })
+    }
+
+    fn read_extension(&mut self) -> Result<Extension, GifError> {
+        let label = self.read_u8(BlockKind::Extension)?;
+        match label {
+            GRAPHIC_CONTROL_LABEL => {
+                let block = BlockKind::GraphicControlExtension;
+                let (block_size, bytes) =
+                    self.read_sized_block::<{ GRAPHIC_CONTROL_BLOCK_SIZE as usize }>(block)?;
+                // Nothing is defined after the fields, so any sub-blocks are skipped.
+                self.read_sub_blocks(block)?;
+                Ok(Extension::GraphicControl(GraphicControlExtension {
+                    extension_introducer: EXTENSION_INTRODUCER,
+                    graphic_control_label: label,
+                    block_size,
+                    packed_fields: GCEPackedFields::from_byte(bytes[0]),
+                    delay_time: u16::from_le_bytes([bytes[1], bytes[2]]),
+                    transparent_color_index: bytes[3],
+                    block_terminator: 0,
+                }))
+            }
+            COMMENT_LABEL => Ok(Extension::Comment(CommentExtension {
+                extension_introducer: EXTENSION_INTRODUCER,
+                comment_label: label,
+                comment_data: self.read_sub_blocks(BlockKind::CommentExtension)?,
+                block_terminator: 0,
+            })),
+            PLAIN_TEXT_LABEL => {
+                let block = BlockKind::PlainTextExtension;
+                let (block_size, bytes) =
+                    self.read_sized_block::<{ PLAIN_TEXT_BLOCK_SIZE as usize }>(block)?;
+                Ok(Extension::PlainText(PlainTextExtension {
+                    extension_introducer: EXTENSION_INTRODUCER,
+                    plain_text_label: label,
+                    block_size,
+                    text_grid_left_position: u16::from_le_bytes([bytes[0], bytes[1]]),
+                    text_grid_top_position: u16::from_le_bytes([bytes[2], bytes[3]]),
+                    text_grid_width: u16::from_le_bytes([bytes[4], bytes[5]]),
+                    text_grid_height: u16::from_le_bytes([bytes[6], bytes[7]]),
+                    character_cell_width: bytes[8],
+                    character_cell_height: bytes[9],
+                    text_foreground_color_index: bytes[10],
+                    text_background_color_index: bytes[11],
+                    text_data: self.read_sub_blocks(block)?,
+                    block_terminator: 0,
+                }))
+            }
+            APPLICATION_LABEL => {
+                let block = BlockKind::ApplicationExtension;
+                let (block_size, bytes) =
+                    self.read_sized_block::<{ APPLICATION_BLOCK_SIZE as usize }>(block)?;
+                Ok(Extension::Application(ApplicationExtension {
+                    extension_introducer: EXTENSION_INTRODUCER,
+                    extension_label: label,
+                    block_size,
+                    application_identifier: [
+                        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6],
+                        bytes[7],
+                    ],
+                    application_authentication_code: [bytes[8], bytes[9], bytes[10]],
+                    application_data: self.read_sub_blocks(block)?,
+                    block_terminator: 0,
+                }))
+            }
+            // Unknown extensions are kept as they are, so they are written back unchanged.
+            _ => Ok(Extension::Unknown(UnknownExtension {
+                extension_introducer: EXTENSION_INTRODUCER,
+                extension_label: label,
+                data: self.read_sub_blocks(BlockKind::UnknownExtension)?,
+                block_terminator: 0,
+            })),
+        }
+    }
+}
+
+// A composited frame: the full canvas as a browser shows it, and how long it is shown.
+pub struct Frame {
+    pub image: RgbaImage,
+    // Hundredths of a second.
+    pub delay: u16,
+}
+
+// Reads a gif one frame at a time, so only the frame being worked on is held in memory. As an
+// iterator it yields the frames as stored, with their extensions; `composited` turns it into one
+// yielding full canvases.
+//
+// The parser reads every field and sub-block length on its own, so the reader is buffered: wrap
+// files in a `BufReader`, byte slices can be passed as they are.
+pub struct GifDecoder<R: BufRead> {
+    parser: Parser<R>,
+    screen: Screen,
+    extensions: Vec<Extension>,
+    // Extensions read since the last image, which belong to the next one.
+    pending: Vec<Extension>,
+    // The first image, when it came before any frame extension and so was read by `new`.
+    first_image: Option<GIFImage>,
+    trailer: Option<Trailer>,
+    failed: bool,
+}
+
+impl<R: BufRead> GifDecoder<R> {
+    // Reads everything up to the first frame, so the screen and the file-level extensions, like
+    // the loop count, are known before any frame is decoded.
+    pub fn new(reader: R) -> Result<GifDecoder<R>, GifError> {
+        let mut parser = Parser::new(reader);
+        let screen = parser.read_screen()?;
+        let mut decoder = GifDecoder {
+            parser,
+            screen,
+            extensions: Vec::new(),
+            pending: Vec::new(),
+            first_image: None,
+            trailer: None,
+            failed: false,
+        };
+        // Extensions ahead of the first graphic control or plain text extension describe the
+        // whole file, the rest belong to the first frame.
+        loop {
+            match decoder.parser.read_block()? {
+                Block::Extension(extension) => {
+                    let frame_extension = matches!(
+                        extension,
+                        Extension::GraphicControl(_) | Extension::PlainText(_)
+                    );
+                    if frame_extension || !decoder.pending.is_empty() {
+                        decoder.pending.push(extension);
+                    } else {
+                        decoder.extensions.push(extension);
+                    }
+                }
+                Block::Image(image) => {
+                    decoder.first_image = Some(image);
+                    break;
+                }
+                Block::Trailer(trailer) => {
+                    decoder.trailer = Some(trailer);
+                    break;
+                }
+            }
+        }
+        Ok(decoder)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.screen.logical_screen_descriptor.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.screen.logical_screen_descriptor.height
+    }
+
+    // Extensions that belong to the whole file rather than a frame.
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    // Also looks at the first frame's extensions, where some encoders put the looping block, so
+    // it is meant to be called before the frames are read.
+    pub fn loop_count(&self) -> LoopCount {
+        loop_count_of(self.extensions.iter().chain(&self.pending))
+    }
+
+    pub fn composited(self) -> CompositedFrames<R> {
+        let compositor = Compositor::new(self.width(), self.height());
+        CompositedFrames {
+            decoder: self,
+            compositor,
+        }
+    }
+
+    fn read_image(&mut self) -> Result<Option<GIFImage>, GifError> {
+        if let Some(mut image) = self.first_image.take() {
+            image.extensions = std::mem::take(&mut self.pending);
+            return Ok(Some(image));
+        }
+        while self.trailer.is_none() {
+            match self.parser.read_block()? {
+                Block::Extension(extension) => self.pending.push(extension),
+                Block::Image(mut image) => {
+                    image.extensions = std::mem::take(&mut self.pending);
+                    return Ok(Some(image));
+                }
+                Block::Trailer(trailer) => self.trailer = Some(trailer),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> Iterator for GifDecoder<R> {
+    type Item = Result<GIFImage, GifError>;
+
+    // Stops after the trailer or the first error.
+    fn next(&mut self) -> Option<Result<GIFImage, GifError>> {
+        if self.failed {
+            return None;
+        }
+        let image = self.read_image();
+        self.failed = image.is_err();
+        image.transpose()
+    }
+}
+
+pub struct CompositedFrames<R: BufRead> {
+    decoder: GifDecoder<R>,
+    compositor: Compositor,
+}
+
+impl<R: BufRead> Iterator for CompositedFrames<R> {
+    type Item = Result<Frame, GifError>;
+
+    fn next(&mut self) -> Option<Result<Frame, GifError>> {
+        let image = match self.decoder.next()? {
+            Ok(image) => image,
+            Err(error) => return Some(Err(error)),
+        };
+        let global_color_table = self.decoder.screen.global_color_table.as_ref();
+        Some(
+            self.compositor
+                .draw(&image, global_color_table)
+                .map(|canvas| Frame {
+                    image: canvas,
+                    delay: image.delay(),
+                }),
+        )
+    }
+}
+
+pub fn read_gif<R: BufRead>(reader: R) -> Result<GIF, GifError> {
+    let mut decoder = GifDecoder::new(reader)?;
+    let images = decoder
+        .by_ref()
+        .collect::<Result<Vec<GIFImage>, GifError>>()?;
+    Ok(GIF {
+        header: decoder.screen.header,
+        logical_screen_descriptor: decoder.screen.logical_screen_descriptor,
+        global_color_table: decoder.screen.global_color_table,
+        extensions: decoder.extensions,
+        images,
+        trailing_extensions: decoder.pending,
+        trailer: decoder.trailer.unwrap_or(Trailer { trailer: TRAILER }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundled(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn reads_gifs_without_a_trailer() {
+        let bytes = bundled("loading-reversed.gif");
+        assert_ne!(bytes.last(), Some(&TRAILER));
+
+        let gif = GIF::from_bytes(&bytes).unwrap();
+        assert!(!gif.images.is_empty());
+        assert_eq!(
+            GifDecoder::new(&bytes[..]).unwrap().composited().count(),
+            gif.images.len()
+        );
+    }
+
+    #[test]
+    fn rejects_gifs_ending_before_the_first_frame() {
+        let bytes = bundled("loading.gif");
+        // The header, logical screen descriptor and global color table.
+        let screen_end = 13 + (3 << ((bytes[10] & 0x07) + 1));
+        assert!(matches!(
+            GIF::from_bytes(&bytes[..screen_end]),
+            Err(GifError::UnexpectedEof {
+                block: BlockKind::BlockIntroducer,
+                ..
+            })
+        ));
+    }
+
+    // The offset of the first graphic control extension's block size byte.
+    fn first_graphic_control_block_size(bytes: &[u8]) -> usize {
+        bytes
+            .windows(2)
+            .position(|window| window == [EXTENSION_INTRODUCER, GRAPHIC_CONTROL_LABEL])
+            .unwrap()
+            + 2
+    }
+
+    #[test]
+    fn skips_bytes_past_the_graphic_control_fields() {
+        let bytes = bundled("loading.gif");
+        let size_offset = first_graphic_control_block_size(&bytes);
+        assert_eq!(bytes[size_offset], GRAPHIC_CONTROL_BLOCK_SIZE);
+
+        // One extra byte in the block and an extra sub-block after it.
+        let mut padded = bytes.clone();
+        padded[size_offset] = GRAPHIC_CONTROL_BLOCK_SIZE + 1;
+        padded.splice(size_offset + 5..size_offset + 5, [0xAA, 2, 0xBB, 0xCC]);
+
+        let gif = GIF::from_bytes(&padded).unwrap();
+        assert_eq!(gif.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_graphic_control_blocks_too_short_for_their_fields() {
+        let mut bytes = bundled("loading.gif");
+        let size_offset = first_graphic_control_block_size(&bytes);
+        bytes[size_offset] = GRAPHIC_CONTROL_BLOCK_SIZE - 1;
+        assert!(matches!(
+            GIF::from_bytes(&bytes),
+            Err(GifError::InvalidStructure {
+                block: BlockKind::GraphicControlExtension,
+                ..
+            })
+        ));
+    }
+}
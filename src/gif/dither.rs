@@ -0,0 +1,143 @@
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DitherMethod {
+    // Every pixel takes its nearest palette color.
+    #[default]
+    None,
+    // Error diffusion spreading all of each pixel's error to its four unvisited neighbours.
+    FloydSteinberg,
+    // Error diffusion spreading three quarters of the error over six neighbours. Keeps more
+    // contrast than Floyd-Steinberg, at the cost of blowing out the darkest and lightest areas.
+    Atkinson,
+    // Ordered dithering with a fixed threshold pattern. Noisier, but a pixel only depends on its
+    // own color and position, so it never changes where the frame does not.
+    Bayer4x4,
+    Bayer8x8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DitherOptions {
+    pub method: DitherMethod,
+    // Scales the error passed on, or the size of the ordered pattern. 0 is no dithering, 1 the
+    // full amount.
+    pub strength: f32,
+    // Frames with too many colors share one palette built over the whole animation, and pixels
+    // that are the same as in the previous frame keep the color they were given there, so static
+    // regions do not shimmer from one frame to the next.
+    pub stable: bool,
+}
+
+impl Default for DitherOptions {
+    fn default() -> DitherOptions {
+        DitherOptions {
+            method: DitherMethod::None,
+            strength: 1.0,
+            stable: true,
+        }
+    }
+}
+
+// Offsets and weights of the neighbours an error diffusion kernel passes error on to.
+const FLOYD_STEINBERG: [(isize, usize, f32); 4] = [
+    (1, 0, 7.0 / 16.0),
+    (-1, 1, 3.0 / 16.0),
+    (0, 1, 5.0 / 16.0),
+    (1, 1, 1.0 / 16.0),
+];
+const ATKINSON: [(isize, usize, f32); 6] = [
+    (1, 0, 1.0 / 8.0),
+    (2, 0, 1.0 / 8.0),
+    (-1, 1, 1.0 / 8.0),
+    (0, 1, 1.0 / 8.0),
+    (1, 1, 1.0 / 8.0),
+    (0, 2, 1.0 / 8.0),
+];
+
+// The position of (x, y) in the ordering of a size x size Bayer matrix, from 0 to size² - 1.
+fn bayer_threshold(x: usize, y: usize, size: usize) -> usize {
+    let mut threshold = 0;
+    for bit in 0..size.trailing_zeros() {
+        let (x, y) = ((x >> bit) & 1, (y >> bit) & 1);
+        threshold = threshold * 4 + 2 * (x ^ y) + y;
+    }
+    threshold
+}
+
+fn to_rgb(color: [f32; 3]) -> [u8; 3] {
+    color.map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+// Maps the pixels of a `width` wide frame onto palette indices, with `nearest` giving the index
+// of the palette color closest to a color. `kept` pixels are not dithered and take the given
+// index as is. Transparent pixels stay `None`.
+pub fn dither(
+    pixels: &[Option<[u8; 3]>],
+    width: usize,
+    palette: &[[u8; 3]],
+    options: &DitherOptions,
+    kept: &[Option<u8>],
+    mut nearest: impl FnMut([u8; 3]) -> u8,
+) -> Vec<Option<u8>> {
+    let strength = options.strength.clamp(0.0, 1.0);
+    let kernel: &[(isize, usize, f32)] = match options.method {
+        DitherMethod::FloydSteinberg => &FLOYD_STEINBERG,
+        DitherMethod::Atkinson => &ATKINSON,
+        _ => &[],
+    };
+    let bayer_size = match options.method {
+        DitherMethod::Bayer4x4 => 4,
+        DitherMethod::Bayer8x8 => 8,
+        _ => 0,
+    };
+    // The pattern spans roughly the distance between neighbouring palette colors.
+    let spread = strength * 255.0 / (palette.len().max(2) as f32).cbrt();
+
+    let mut errors = vec![[0f32; 3]; if kernel.is_empty() { 0 } else { pixels.len() }];
+    let mut indices = Vec::with_capacity(pixels.len());
+    for (i, pixel) in pixels.iter().enumerate() {
+        let Some(rgb) = *pixel else {
+            indices.push(None);
+            continue;
+        };
+        if let Some(index) = kept.get(i).copied().flatten() {
+            indices.push(Some(index));
+            continue;
+        }
+        let (x, y) = (i % width, i / width);
+        let mut color = rgb.map(|channel| channel as f32);
+
+        if bayer_size > 0 {
+            let threshold = bayer_threshold(x, y, bayer_size) as f32;
+            let offset = ((threshold + 0.5) / (bayer_size * bayer_size) as f32 - 0.5) * spread;
+            indices.push(Some(nearest(to_rgb(color.map(|channel| channel + offset)))));
+            continue;
+        }
+        if kernel.is_empty() || strength == 0.0 {
+            indices.push(Some(nearest(rgb)));
+            continue;
+        }
+
+        for (channel, error) in color.iter_mut().zip(errors[i]) {
+            *channel = (*channel + error).clamp(0.0, 255.0);
+        }
+        let index = nearest(to_rgb(color));
+        indices.push(Some(index));
+        let chosen = palette[index as usize];
+        for &(dx, dy, weight) in kernel {
+            let neighbour_x = x as isize + dx;
+            let neighbour_y = y + dy;
+            if neighbour_x < 0 || neighbour_x as usize >= width {
+                continue;
+            }
+            let neighbour = neighbour_y * width + neighbour_x as usize;
+            // Error is not passed on to transparent pixels.
+            if !matches!(pixels.get(neighbour), Some(Some(_))) {
+                continue;
+            }
+            for channel in 0..3 {
+                let error = (color[channel] - chosen[channel] as f32) * weight * strength;
+                errors[neighbour][channel] += error;
+            }
+        }
+    }
+    indices
+}
@@ -0,0 +1,641 @@
+use std::io::{self, Write};
+
+use image::RgbaImage;
+
+use super::builder::{color_table_size, padded_colors, StreamedFrames};
+use super::{
+    BlockKind, Color, Extension, GCEPackedFields, GIFDataSubBlock, GIFHeader,
+    GIFImage, GifError, GlobalColorTable, IDPackedFields, LSDPackedFields, LogicalScreenDescriptor,
+    LoopCount, APPLICATION_BLOCK_SIZE, APPLICATION_LABEL, COMMENT_LABEL, EXTENSION_INTRODUCER, GIF,
+    GIF_87A_VERSION, GIF_89A_VERSION, GIF_SIGNATURE, GRAPHIC_CONTROL_BLOCK_SIZE,
+    GRAPHIC_CONTROL_LABEL, IMAGE_SEPARATOR, PLAIN_TEXT_BLOCK_SIZE, PLAIN_TEXT_LABEL, TRAILER,
+};
+
+// Writes a gif block by block, so frames can be encoded as they are made instead of holding the
+// whole animation. The header, logical screen descriptor and global color table are written when
+// the encoder is created, and `finish` writes the trailer.
+//
+// Block sizes, sub-block lengths, color table flags and sizes are worked out from the data rather
+// than taken from the stored fields, and blocks that cannot be written as valid GIF are refused.
+// Each block is checked before any of it is written.
+pub struct GifEncoder<W: Write> {
+    writer: W,
+    width: u16,
+    height: u16,
+    has_global_color_table: bool,
+    frame_count: usize,
+    // Frames from `add_frame`, the last of which is held until the next shows how to dispose of
+    // it, along with the extensions written after it.
+    frames: StreamedFrames,
+    held_extensions: Vec<u8>,
+}
+
+fn invalid(block: BlockKind, message: String) -> GifError {
+    GifError::InvalidStructure { block, message }
+}
+
+// The size field of a color table, for which the table must hold a power of two colors from 2
+// to 256.
+fn color_table_size_field(colors: &[Color], block: BlockKind) -> Result<u8, GifError> {
+    let size = color_table_size(colors.len());
+    if colors.len() != 2 << size || size > 7 {
+        return Err(invalid(
+            block,
+            format!(
+                "{} colors, a color table holds a power of two from 2 to 256",
+                colors.len()
+            ),
+        ));
+    }
+    Ok(size)
+}
+
+fn push_colors(bytes: &mut Vec<u8>, colors: &[Color]) {
+    for color in colors {
+        bytes.extend_from_slice(&[color.red, color.green, color.blue]);
+    }
+}
+
+// Writes each sub-block with its length and the 0 length block that ends them.
+fn push_sub_blocks(
+    bytes: &mut Vec<u8>,
+    sub_blocks: &[GIFDataSubBlock],
+    block: BlockKind,
+) -> Result<(), GifError> {
+    for sub_block in sub_blocks {
+        let length = sub_block.data.len();
+        if length == 0 || length > 255 {
+            return Err(invalid(
+                block,
+                format!("a data sub-block holds 1 to 255 bytes, got {}", length),
+            ));
+        }
+        bytes.push(length as u8);
+        bytes.extend_from_slice(&sub_block.data);
+    }
+    bytes.push(0);
+    Ok(())
+}
+
+fn check_field(value: u8, max: u8, name: &str, block: BlockKind) -> Result<(), GifError> {
+    if value > max {
+        return Err(invalid(
+            block,
+            format!("{} is {}, at most {} fits", name, value, max),
+        ));
+    }
+    Ok(())
+}
+
+fn graphic_control_byte(packed_fields: &GCEPackedFields) -> Result<u8, GifError> {
+    let block = BlockKind::GraphicControlExtension;
+    check_field(packed_fields.reserved, 0b111, "the reserved field", block)?;
+    check_field(
+        packed_fields.disposal_method,
+        0b111,
+        "the disposal method",
+        block,
+    )?;
+    Ok(packed_fields.to_byte())
+}
+
+fn extension_bytes(extension: &Extension) -> Result<Vec<u8>, GifError> {
+    let mut bytes = vec![EXTENSION_INTRODUCER];
+    match extension {
+        Extension::GraphicControl(graphic_control_extension) => {
+            bytes.push(GRAPHIC_CONTROL_LABEL);
+            bytes.push(GRAPHIC_CONTROL_BLOCK_SIZE);
+            bytes.push(graphic_control_byte(
+                &graphic_control_extension.packed_fields,
+            )?);
+            bytes.extend_from_slice(&graphic_control_extension.delay_time.to_le_bytes());
+            bytes.push(graphic_control_extension.transparent_color_index);
+            bytes.push(0);
+        }
+        Extension::Comment(comment_extension) => {
+            bytes.push(COMMENT_LABEL);
+            push_sub_blocks(
+                &mut bytes,
+                &comment_extension.comment_data,
+                BlockKind::CommentExtension,
+            )?;
+        }
+        Extension::PlainText(plain_text_extension) => {
+            bytes.push(PLAIN_TEXT_LABEL);
+            bytes.push(PLAIN_TEXT_BLOCK_SIZE);
+            bytes.extend_from_slice(&plain_text_extension.text_grid_left_position.to_le_bytes());
+            bytes.extend_from_slice(&plain_text_extension.text_grid_top_position.to_le_bytes());
+            bytes.extend_from_slice(&plain_text_extension.text_grid_width.to_le_bytes());
+            bytes.extend_from_slice(&plain_text_extension.text_grid_height.to_le_bytes());
+            bytes.push(plain_text_extension.character_cell_width);
+            bytes.push(plain_text_extension.character_cell_height);
+            bytes.push(plain_text_extension.text_foreground_color_index);
+            bytes.push(plain_text_extension.text_background_color_index);
+            push_sub_blocks(
+                &mut bytes,
+                &plain_text_extension.text_data,
+                BlockKind::PlainTextExtension,
+            )?;
+        }
+        Extension::Application(application_extension) => {
+            bytes.push(APPLICATION_LABEL);
+            bytes.push(APPLICATION_BLOCK_SIZE);
+            bytes.extend_from_slice(&application_extension.application_identifier);
+            bytes.extend_from_slice(&application_extension.application_authentication_code);
+            push_sub_blocks(
+                &mut bytes,
+                &application_extension.application_data,
+                BlockKind::ApplicationExtension,
+            )?;
+        }
+        Extension::Unknown(unknown_extension) => {
+            let label = unknown_extension.extension_label;
+            if [
+                GRAPHIC_CONTROL_LABEL,
+                COMMENT_LABEL,
+                PLAIN_TEXT_LABEL,
+                APPLICATION_LABEL,
+            ]
+            .contains(&label)
+            {
+                return Err(invalid(
+                    BlockKind::UnknownExtension,
+                    format!("label 0x{:02X} belongs to a known extension", label),
+                ));
+            }
+            bytes.push(label);
+            push_sub_blocks(
+                &mut bytes,
+                &unknown_extension.data,
+                BlockKind::UnknownExtension,
+            )?;
+        }
+    }
+    Ok(bytes)
+}
+
+impl<W: Write> GifEncoder<W> {
+    // Starts a GIF89a of the given size. The global color table is padded to the next power of
+    // two; frames without a local color table use it.
+    pub fn new(
+        writer: W,
+        width: u16,
+        height: u16,
+        global_colors: Option<&[Color]>,
+    ) -> Result<GifEncoder<W>, GifError> {
+        let global_color_table = match global_colors {
+            Some(colors) if colors.len() > 256 => {
+                return Err(GifError::InvalidInput {
+                    message: format!(
+                        "A color table holds at most 256 colors, got {}",
+                        colors.len()
+                    ),
+                })
+            }
+            Some(colors) => {
+                // A slot is kept free for the transparent index of added frames where there is
+                // room for one.
+                let size = color_table_size((colors.len() + 1).min(256));
+                Some(GlobalColorTable {
+                    colors: padded_colors(colors, size),
+                })
+            }
+            None => None,
+        };
+        let header = GIFHeader {
+            signature: GIF_SIGNATURE,
+            version: GIF_89A_VERSION,
+        };
+        let logical_screen_descriptor = LogicalScreenDescriptor {
+            width,
+            height,
+            packed_fields: LSDPackedFields {
+                global_color_table_flag: global_color_table.is_some(),
+                color_resolution: 7,
+                sort_flag: false,
+                size_of_global_color_table: 0,
+            },
+            background_color_index: 0,
+            pixel_aspect_ratio: 0,
+        };
+        let mut encoder = GifEncoder::start(
+            writer,
+            &header,
+            &logical_screen_descriptor,
+            global_color_table.as_ref(),
+            true,
+        )?;
+        encoder.frames = StreamedFrames::new(width, height, global_colors);
+        Ok(encoder)
+    }
+
+    // Extensions only exist in GIF89a, so the version is raised when the gif will have any.
+    fn start(
+        mut writer: W,
+        header: &GIFHeader,
+        logical_screen_descriptor: &LogicalScreenDescriptor,
+        global_color_table: Option<&GlobalColorTable>,
+        has_extensions: bool,
+    ) -> Result<GifEncoder<W>, GifError> {
+        let version = if has_extensions {
+            GIF_89A_VERSION
+        } else if header.version == GIF_87A_VERSION || header.version == GIF_89A_VERSION {
+            header.version
+        } else {
+            return Err(invalid(
+                BlockKind::Header,
+                format!(
+                    "unknown version {}",
+                    String::from_utf8_lossy(&header.version)
+                ),
+            ));
+        };
+
+        let packed_fields = &logical_screen_descriptor.packed_fields;
+        check_field(
+            packed_fields.color_resolution,
+            0b111,
+            "the color resolution",
+            BlockKind::LogicalScreenDescriptor,
+        )?;
+        let size_of_global_color_table = match global_color_table {
+            Some(global_color_table) => {
+                color_table_size_field(&global_color_table.colors, BlockKind::GlobalColorTable)?
+            }
+            None => 0,
+        };
+        let packed_fields = LSDPackedFields {
+            global_color_table_flag: global_color_table.is_some(),
+            size_of_global_color_table,
+            ..packed_fields.clone()
+        };
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&GIF_SIGNATURE);
+        bytes.extend_from_slice(&version);
+        bytes.extend_from_slice(&logical_screen_descriptor.width.to_le_bytes());
+        bytes.extend_from_slice(&logical_screen_descriptor.height.to_le_bytes());
+        bytes.push(packed_fields.to_byte());
+        bytes.push(logical_screen_descriptor.background_color_index);
+        bytes.push(logical_screen_descriptor.pixel_aspect_ratio);
+        if let Some(global_color_table) = global_color_table {
+            push_colors(&mut bytes, &global_color_table.colors);
+        }
+        writer.write_all(&bytes)?;
+
+        Ok(GifEncoder {
+            writer,
+            width: logical_screen_descriptor.width,
+            height: logical_screen_descriptor.height,
+            has_global_color_table: global_color_table.is_some(),
+            frame_count: 0,
+            frames: StreamedFrames::new(
+                logical_screen_descriptor.width,
+                logical_screen_descriptor.height,
+                None,
+            ),
+            held_extensions: Vec::new(),
+        })
+    }
+
+    // Writes the NETSCAPE2.0 looping block. Browsers only look for it before the first frame.
+    pub fn write_loop_count(&mut self, loop_count: LoopCount) -> Result<(), GifError> {
+        if self.frame_count > 0 || self.frames.is_pending() {
+            return Err(GifError::InvalidInput {
+                message: "The loop count must be written before the first frame".to_string(),
+            });
+        }
+        match Extension::looping(loop_count) {
+            Some(extension) => self.write_extension(&extension),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write_extension(&mut self, extension: &Extension) -> Result<(), GifError> {
+        let bytes = extension_bytes(extension)?;
+        if self.frames.is_pending() {
+            self.held_extensions.extend_from_slice(&bytes);
+        } else {
+            self.writer.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    // Writes the frame's extensions, image descriptor, local color table and image data. A frame
+    // held back by `add_frame` is written first, and the next added frame is stored whole.
+    pub fn write_image(&mut self, image: &GIFImage) -> Result<(), GifError> {
+        self.write_held_frame()?;
+        self.write_image_block(image)
+    }
+
+    fn write_image_block(&mut self, image: &GIFImage) -> Result<(), GifError> {
+        let mut bytes = Vec::new();
+        for extension in &image.extensions {
+            bytes.extend_from_slice(&extension_bytes(extension)?);
+        }
+
+        let descriptor = &image.image_descriptor;
+        let right = descriptor.left_position as u32 + descriptor.width as u32;
+        let bottom = descriptor.top_position as u32 + descriptor.height as u32;
+        if right > self.width as u32 || bottom > self.height as u32 {
+            return Err(invalid(
+                BlockKind::ImageDescriptor,
+                format!(
+                    "a {}x{} frame at {},{} does not fit the {}x{} screen",
+                    descriptor.width,
+                    descriptor.height,
+                    descriptor.left_position,
+                    descriptor.top_position,
+                    self.width,
+                    self.height
+                ),
+            ));
+        }
+        if image.local_color_table.is_none() && !self.has_global_color_table {
+            return Err(GifError::MissingColorTable);
+        }
+        check_field(
+            descriptor.packed_fields.reserved,
+            0b11,
+            "the reserved field",
+            BlockKind::ImageDescriptor,
+        )?;
+        let size_of_local_color_table = match &image.local_color_table {
+            Some(local_color_table) => {
+                color_table_size_field(&local_color_table.colors, BlockKind::LocalColorTable)?
+            }
+            None => 0,
+        };
+        let packed_fields = IDPackedFields {
+            local_color_table_flag: image.local_color_table.is_some(),
+            size_of_local_color_table,
+            ..descriptor.packed_fields.clone()
+        };
+
+        bytes.push(IMAGE_SEPARATOR);
+        bytes.extend_from_slice(&descriptor.left_position.to_le_bytes());
+        bytes.extend_from_slice(&descriptor.top_position.to_le_bytes());
+        bytes.extend_from_slice(&descriptor.width.to_le_bytes());
+        bytes.extend_from_slice(&descriptor.height.to_le_bytes());
+        bytes.push(packed_fields.to_byte());
+        if let Some(local_color_table) = &image.local_color_table {
+            push_colors(&mut bytes, &local_color_table.colors);
+        }
+
+        let lzw_minimum_code_size = image.image_data.lzw_minimum_code_size;
+        if !(2..=8).contains(&lzw_minimum_code_size) {
+            return Err(invalid(
+                BlockKind::ImageData,
+                format!(
+                    "the lzw minimum code size is {}, it must be 2 to 8",
+                    lzw_minimum_code_size
+                ),
+            ));
+        }
+        bytes.push(lzw_minimum_code_size);
+        push_sub_blocks(
+            &mut bytes,
+            &image.image_data.sub_blocks,
+            BlockKind::ImageData,
+        )?;
+
+        self.writer.write_all(&bytes)?;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    // Encodes a full canvas frame as only the rectangle that changed since the frame before, with
+    // unchanged pixels inside it made transparent, the same way `GIF::from_frames` does. Frames
+    // draw from the global color table when it holds all of their colors, and otherwise get their
+    // own. Each frame is written once the next one is added, or by `finish`.
+    pub fn add_frame(&mut self, frame: &RgbaImage, delay: u16) -> Result<(), GifError> {
+        if (frame.width(), frame.height()) != (self.width as u32, self.height as u32) {
+            return Err(GifError::InvalidInput {
+                message: format!(
+                    "Frame size {}x{} differs from the gif size {}x{}",
+                    frame.width(),
+                    frame.height(),
+                    self.width,
+                    self.height
+                ),
+            });
+        }
+        match self.frames.push(frame, delay) {
+            Some(image) => self.write_released_frame(&image),
+            None => Ok(()),
+        }
+    }
+
+    fn write_held_frame(&mut self) -> Result<(), GifError> {
+        match self.frames.finish() {
+            Some(image) => self.write_released_frame(&image),
+            None => Ok(()),
+        }
+    }
+
+    // Writes a frame from `add_frame` and the extensions written after it.
+    fn write_released_frame(&mut self, image: &GIFImage) -> Result<(), GifError> {
+        self.write_image_block(image)?;
+        self.writer.write_all(&self.held_extensions)?;
+        self.held_extensions.clear();
+        Ok(())
+    }
+
+    // Writes the last added frame and the trailer, and hands back the writer.
+    pub fn finish(mut self) -> Result<W, GifError> {
+        self.write_held_frame()?;
+        self.writer.write_all(&[TRAILER])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl GIF {
+    // Streams the gif block by block. Every block is checked in a first pass that writes nothing,
+    // so an invalid one leaves the writer untouched rather than holding a gif cut short, and only
+    // I/O errors can stop the second pass part way.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<W, GifError> {
+        self.encode(io::sink())?;
+        self.encode(writer)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, GifError> {
+        self.encode(Vec::new())
+    }
+
+    fn encode<W: Write>(&self, writer: W) -> Result<W, GifError> {
+        let has_extensions = !self.extensions.is_empty()
+            || !self.trailing_extensions.is_empty()
+            || self.images.iter().any(|image| !image.extensions.is_empty());
+        let mut encoder = GifEncoder::start(
+            writer,
+            &self.header,
+            &self.logical_screen_descriptor,
+            self.global_color_table.as_ref(),
+            has_extensions,
+        )?;
+        for extension in &self.extensions {
+            encoder.write_extension(extension)?;
+        }
+        for image in &self.images {
+            encoder.write_image(image)?;
+        }
+        for extension in &self.trailing_extensions {
+            encoder.write_extension(extension)?;
+        }
+        encoder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::CommentExtension;
+    use super::*;
+
+    fn bundled(name: &str) -> GIF {
+        GIF::from_file(&format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    // A gif whose last frame cannot be written, so every frame before it is valid.
+    fn invalid_gif() -> GIF {
+        let mut gif = bundled("loading.gif");
+        gif.images.last_mut().unwrap().image_data.lzw_minimum_code_size = 9;
+        gif
+    }
+
+    #[test]
+    fn write_to_streams_the_same_bytes_as_to_bytes() {
+        let gif = bundled("loading.gif");
+        assert_eq!(gif.write_to(Vec::new()).unwrap(), gif.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn invalid_gifs_write_nothing() {
+        let mut written = Vec::new();
+        assert!(matches!(
+            invalid_gif().write_to(&mut written),
+            Err(GifError::InvalidStructure {
+                block: BlockKind::ImageData,
+                ..
+            })
+        ));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn invalid_gifs_leave_saved_files_untouched() {
+        let directory = std::env::temp_dir().join(format!("gifcap-save-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("saved.gif");
+        let path = path.to_str().unwrap();
+
+        let gif = bundled("loading.gif");
+        gif.save(path).unwrap();
+        assert!(invalid_gif().save(path).is_err());
+
+        assert_eq!(std::fs::read(path).unwrap(), gif.to_bytes().unwrap());
+        // Only the saved gif is left, without a temporary file.
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn parsed_gifs_encode_to_the_same_bytes() {
+        for name in ["loading.gif", "whar.gif"] {
+            let bytes = std::fs::read(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+            let gif = GIF::from_bytes(&bytes).unwrap();
+            assert!(gif.to_bytes().unwrap() == bytes, "{} changed when re-encoded", name);
+        }
+    }
+
+    // A green square moving over red, with a transparent hole in the last frame.
+    fn moving_square() -> Vec<RgbaImage> {
+        (0..4)
+            .map(|step| {
+                RgbaImage::from_fn(16, 16, |x, y| {
+                    if step == 3 && x < 4 && y < 4 {
+                        image::Rgba([0, 0, 0, 0])
+                    } else if (3 * step..3 * step + 4).contains(&x) && (6..10).contains(&y) {
+                        image::Rgba([0, 255, 0, 255])
+                    } else {
+                        image::Rgba([255, 0, 0, 255])
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn stream(frames: &[RgbaImage], global_colors: Option<&[Color]>) -> GIF {
+        let mut encoder = GifEncoder::new(Vec::new(), 16, 16, global_colors).unwrap();
+        for frame in frames {
+            encoder.add_frame(frame, 10).unwrap();
+        }
+        GIF::from_bytes(&encoder.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn added_frames_draw_from_the_global_table_and_store_what_changed() {
+        let frames = moving_square();
+        let global_colors = [
+            Color {
+                red: 255,
+                green: 0,
+                blue: 0,
+            },
+            Color {
+                red: 0,
+                green: 255,
+                blue: 0,
+            },
+        ];
+        let gif = stream(&frames, Some(&global_colors));
+
+        assert!(gif.frames().unwrap() == frames);
+        assert_eq!(gif.delays(), [10; 4]);
+        assert!(gif.images.iter().all(|image| image.local_color_table.is_none()));
+        // The second frame only stores where the square moved. The third is widened to clear
+        // the canvas for the hole in the last.
+        let descriptor = &gif.images[1].image_descriptor;
+        assert!(descriptor.width < 16 && descriptor.height < 16);
+    }
+
+    #[test]
+    fn added_frames_with_colors_outside_the_global_table_get_their_own() {
+        let frames = moving_square();
+        let global_colors = [Color {
+            red: 255,
+            green: 0,
+            blue: 0,
+        }];
+        let gif = stream(&frames, Some(&global_colors));
+        assert!(gif.frames().unwrap() == frames);
+        assert!(gif.images[0].local_color_table.is_some());
+
+        assert!(stream(&frames, None).frames().unwrap() == frames);
+    }
+
+    #[test]
+    fn extensions_written_between_added_frames_stay_between_them() {
+        let frames = moving_square();
+        let comment = Extension::Comment(CommentExtension {
+            extension_introducer: EXTENSION_INTRODUCER,
+            comment_label: COMMENT_LABEL,
+            comment_data: vec![GIFDataSubBlock {
+                size: 2,
+                data: b"hi".to_vec(),
+            }],
+            block_terminator: 0,
+        });
+        let mut encoder = GifEncoder::new(Vec::new(), 16, 16, None).unwrap();
+        encoder.add_frame(&frames[0], 10).unwrap();
+        encoder.write_extension(&comment).unwrap();
+        encoder.add_frame(&frames[1], 10).unwrap();
+        let gif = GIF::from_bytes(&encoder.finish().unwrap()).unwrap();
+
+        assert!(gif.frames().unwrap() == frames[..2]);
+        assert!(matches!(
+            gif.images[1].extensions[0],
+            Extension::Comment(_)
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{error, fmt, io};
+
+// The part of the file being read when decoding failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Header,
+    LogicalScreenDescriptor,
+    GlobalColorTable,
+    ImageDescriptor,
+    LocalColorTable,
+    ImageData,
+    // An extension whose label has not been read yet.
+    Extension,
+    GraphicControlExtension,
+    CommentExtension,
+    PlainTextExtension,
+    ApplicationExtension,
+    UnknownExtension,
+    // The byte that says whether an image, an extension or the trailer comes next.
+    BlockIntroducer,
+    Trailer,
+}
+
+#[derive(Debug)]
+pub enum GifError {
+    Io(io::Error),
+    // The data ended in the middle of a block. The offset is where the read that ran out started.
+    UnexpectedEof { offset: u64, block: BlockKind },
+    BadSignature { offset: u64 },
+    // A byte where a block should start that is not an image, an extension or the trailer.
+    UnknownBlock { offset: u64, introducer: u8 },
+    // The frame and the offset of its image data in the file are known for data that was read
+    // from a file.
+    InvalidLzw { frame: Option<usize>, offset: Option<u64>, message: String },
+    // A block that cannot be written as stored, such as a color table whose length is not a
+    // power of two, or one read with a block size too small for its fields.
+    InvalidStructure { block: BlockKind, message: String },
+    // A frame has no local color table and the gif has no global color table.
+    MissingColorTable,
+    NoFrames,
+    // Arguments an operation cannot work with, such as a size of 0 or a negative speed.
+    InvalidInput { message: String },
+}
+
+impl fmt::Display for BlockKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BlockKind::Header => "header",
+            BlockKind::LogicalScreenDescriptor => "logical screen descriptor",
+            BlockKind::GlobalColorTable => "global color table",
+            BlockKind::ImageDescriptor => "image descriptor",
+            BlockKind::LocalColorTable => "local color table",
+            BlockKind::ImageData => "image data",
+            BlockKind::Extension => "extension",
+            BlockKind::GraphicControlExtension => "graphic control extension",
+            BlockKind::CommentExtension => "comment extension",
+            BlockKind::PlainTextExtension => "plain text extension",
+            BlockKind::ApplicationExtension => "application extension",
+            BlockKind::UnknownExtension => "unknown extension",
+            BlockKind::BlockIntroducer => "block introducer",
+            BlockKind::Trailer => "trailer",
+        };
+        formatter.write_str(name)
+    }
+}
+
+impl fmt::Display for GifError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GifError::Io(error) => write!(formatter, "{}", error),
+            GifError::UnexpectedEof { offset, block } => write!(
+                formatter,
+                "Unexpected end of data in the {} at byte {}",
+                block, offset
+            ),
+            GifError::BadSignature { offset } => {
+                write!(formatter, "Invalid gif signature at byte {}", offset)
+            }
+            GifError::UnknownBlock { offset, introducer } => write!(
+                formatter,
+                "Unknown block introducer 0x{:02X} at byte {}",
+                introducer, offset
+            ),
+            GifError::InvalidLzw {
+                frame,
+                offset,
+                message,
+            } => {
+                write!(formatter, "Invalid lzw data")?;
+                if let Some(frame) = frame {
+                    write!(formatter, " in frame {}", frame)?;
+                }
+                if let Some(offset) = offset {
+                    write!(formatter, " at byte {}", offset)?;
+                }
+                write!(formatter, ": {}", message)
+            }
+            GifError::InvalidStructure { block, message } => {
+                write!(formatter, "Invalid {}: {}", block, message)
+            }
+            GifError::MissingColorTable => write!(
+                formatter,
+                "Frame has no local color table and there is no global color table"
+            ),
+            GifError::NoFrames => write!(formatter, "The gif has no frames"),
+            GifError::InvalidInput { message } => write!(formatter, "{}", message),
+        }
+    }
+}
+
+impl error::Error for GifError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            GifError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GifError {
+    fn from(error: io::Error) -> GifError {
+        GifError::Io(error)
+    }
+}
@@ -0,0 +1,151 @@
+use super::{
+    ApplicationExtension, Extension, GIFDataSubBlock, APPLICATION_BLOCK_SIZE, APPLICATION_LABEL,
+    EXTENSION_INTRODUCER, GIF, GIF_89A_VERSION,
+};
+
+// Both blocks carry the same looping sub-block: the id 1 followed by the loop count as a little
+// endian u16, where 0 means forever.
+const NETSCAPE_IDENTIFIER: [u8; 8] = *b"NETSCAPE";
+const NETSCAPE_AUTHENTICATION_CODE: [u8; 3] = *b"2.0";
+const ANIMEXTS_IDENTIFIER: [u8; 8] = *b"ANIMEXTS";
+const ANIMEXTS_AUTHENTICATION_CODE: [u8; 3] = *b"1.0";
+const LOOPING_SUB_BLOCK_ID: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopCount {
+    Infinite,
+    // The number of times the animation is played in total. Values below 2 are the same as Once.
+    Finite(u32),
+    // Gifs without a looping block play once.
+    Once,
+}
+
+impl ApplicationExtension {
+    fn is_looping(&self) -> bool {
+        (
+            self.application_identifier,
+            self.application_authentication_code,
+        ) == (NETSCAPE_IDENTIFIER, NETSCAPE_AUTHENTICATION_CODE)
+            || (
+                self.application_identifier,
+                self.application_authentication_code,
+            ) == (ANIMEXTS_IDENTIFIER, ANIMEXTS_AUTHENTICATION_CODE)
+    }
+
+    // The loop count stored in the block, as repetitions after the first play.
+    fn repetitions(&self) -> Option<u16> {
+        self.application_data
+            .iter()
+            .find(|sub_block| {
+                sub_block.data.len() >= 3 && sub_block.data[0] == LOOPING_SUB_BLOCK_ID
+            })
+            .map(|sub_block| u16::from_le_bytes([sub_block.data[1], sub_block.data[2]]))
+    }
+
+    fn looping_data(repetitions: u16) -> Vec<GIFDataSubBlock> {
+        let [low, high] = repetitions.to_le_bytes();
+        vec![GIFDataSubBlock {
+            size: 3,
+            data: vec![LOOPING_SUB_BLOCK_ID, low, high],
+        }]
+    }
+}
+
+impl Extension {
+    // The looping block for the loop count, None when the gif plays once.
+    pub fn looping(loop_count: LoopCount) -> Option<Extension> {
+        repetitions(loop_count).map(|repetitions| {
+            Extension::Application(ApplicationExtension {
+                extension_introducer: EXTENSION_INTRODUCER,
+                extension_label: APPLICATION_LABEL,
+                block_size: APPLICATION_BLOCK_SIZE,
+                application_identifier: NETSCAPE_IDENTIFIER,
+                application_authentication_code: NETSCAPE_AUTHENTICATION_CODE,
+                application_data: ApplicationExtension::looping_data(repetitions),
+                block_terminator: 0,
+            })
+        })
+    }
+}
+
+// The loop count as stored in the looping block, None when there should be no block.
+fn repetitions(loop_count: LoopCount) -> Option<u16> {
+    match loop_count {
+        LoopCount::Infinite => Some(0),
+        LoopCount::Finite(plays) if plays >= 2 => Some((plays - 1).min(u16::MAX as u32) as u16),
+        LoopCount::Finite(_) | LoopCount::Once => None,
+    }
+}
+
+fn looping_extension(extension: &Extension) -> Option<&ApplicationExtension> {
+    match extension {
+        Extension::Application(application_extension) if application_extension.is_looping() => {
+            Some(application_extension)
+        }
+        _ => None,
+    }
+}
+
+// The loop count given by the first looping block among the extensions.
+pub fn loop_count_of<'a>(extensions: impl Iterator<Item = &'a Extension>) -> LoopCount {
+    let repetitions = extensions
+        .filter_map(looping_extension)
+        .find_map(ApplicationExtension::repetitions);
+    match repetitions {
+        Some(0) => LoopCount::Infinite,
+        Some(repetitions) => LoopCount::Finite(repetitions as u32 + 1),
+        None => LoopCount::Once,
+    }
+}
+
+impl GIF {
+    pub fn loop_count(&self) -> LoopCount {
+        loop_count_of(
+            self.extensions
+                .iter()
+                .chain(self.images.iter().flat_map(|image| &image.extensions)),
+        )
+    }
+
+    // Writes the looping block right after the global color table, ahead of every frame, which is
+    // where browsers look for it. An existing NETSCAPE2.0 or ANIMEXTS1.0 block is updated in
+    // place, and any stray copies on frames are removed.
+    pub fn set_loop_count(&mut self, loop_count: LoopCount) {
+        for image in &mut self.images {
+            image
+                .extensions
+                .retain(|extension| looping_extension(extension).is_none());
+        }
+
+        let repetitions = match repetitions(loop_count) {
+            Some(repetitions) => repetitions,
+            None => {
+                self.extensions
+                    .retain(|extension| looping_extension(extension).is_none());
+                return;
+            }
+        };
+
+        let mut updated = false;
+        self.extensions.retain_mut(|extension| match extension {
+            Extension::Application(application_extension) if application_extension.is_looping() => {
+                if updated {
+                    return false;
+                }
+                application_extension.application_data =
+                    ApplicationExtension::looping_data(repetitions);
+                updated = true;
+                true
+            }
+            _ => true,
+        });
+
+        if !updated {
+            if let Some(extension) = Extension::looping(loop_count) {
+                self.extensions.insert(0, extension);
+            }
+        }
+        // Extensions only exist in GIF89a.
+        self.header.version = GIF_89A_VERSION;
+    }
+}
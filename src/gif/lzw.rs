@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+
+use super::GifError;
+
+const MAX_CODE_SIZE: u8 = 12;
+const MAX_CODES: usize = 1 << MAX_CODE_SIZE;
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    buffered_bits: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            buffered_bits: 0,
+        }
+    }
+
+    fn read(&mut self, bits: u8) -> Option<u16> {
+        while self.buffered_bits < bits {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.buffered_bits;
+            self.buffered_bits += 8;
+        }
+        let code = (self.buffer & ((1 << bits) - 1)) as u16;
+        self.buffer >>= bits;
+        self.buffered_bits -= bits;
+        Some(code)
+    }
+}
+
+// Decodes GIF flavoured variable-width LZW data into color indices. Data that ends without an
+// end-of-information code is accepted, as every browser does, and decoding stops there.
+pub fn decode(lzw_minimum_code_size: u8, data: &[u8]) -> Result<Vec<u8>, GifError> {
+    if !(2..=8).contains(&lzw_minimum_code_size) {
+        return Err(GifError::InvalidLzw {
+            frame: None,
+            offset: None,
+            message: format!(
+                "minimum code size {} is out of range",
+                lzw_minimum_code_size
+            ),
+        });
+    }
+
+    let clear_code = 1u16 << lzw_minimum_code_size;
+    let end_code = clear_code + 1;
+
+    let mut prefixes = [0u16; MAX_CODES];
+    let mut suffixes = [0u8; MAX_CODES];
+    let mut first_bytes = [0u8; MAX_CODES];
+    let mut lengths = [0u16; MAX_CODES];
+    for code in 0..clear_code {
+        suffixes[code as usize] = code as u8;
+        first_bytes[code as usize] = code as u8;
+        lengths[code as usize] = 1;
+    }
+
+    let mut reader = BitReader::new(data);
+    let mut code_size = lzw_minimum_code_size + 1;
+    let mut next_code = end_code + 1;
+    let mut previous: Option<u16> = None;
+    let mut indices = Vec::with_capacity(data.len() * 2);
+
+    while let Some(code) = reader.read(code_size) {
+        if code == clear_code {
+            code_size = lzw_minimum_code_size + 1;
+            next_code = end_code + 1;
+            previous = None;
+            continue;
+        }
+        if code == end_code {
+            break;
+        }
+
+        let previous_code = match previous {
+            Some(previous_code) => previous_code,
+            None => {
+                if code >= clear_code {
+                    return Err(GifError::InvalidLzw {
+                        frame: None,
+                        offset: None,
+                        message: format!("code {} after clear code", code),
+                    });
+                }
+                indices.push(code as u8);
+                previous = Some(code);
+                continue;
+            }
+        };
+
+        if code > next_code || (code == next_code && next_code as usize >= MAX_CODES) {
+            return Err(GifError::InvalidLzw {
+                frame: None,
+                offset: None,
+                message: format!("code {}, next free code is {}", code, next_code),
+            });
+        }
+
+        // A full table stays frozen until the encoder sends a clear code (deferred clear), so no
+        // new entries are added while next_code sits at the limit.
+        let table_full = next_code as usize >= MAX_CODES;
+        if !table_full {
+            let first_byte = if code == next_code {
+                first_bytes[previous_code as usize]
+            } else {
+                first_bytes[code as usize]
+            };
+            let new_code = next_code as usize;
+            prefixes[new_code] = previous_code;
+            suffixes[new_code] = first_byte;
+            first_bytes[new_code] = first_bytes[previous_code as usize];
+            lengths[new_code] = lengths[previous_code as usize] + 1;
+            next_code += 1;
+            if next_code as usize == 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+        }
+
+        let length = lengths[code as usize] as usize;
+        let start = indices.len();
+        indices.resize(start + length, 0);
+        let mut current = code;
+        for slot in indices[start..].iter_mut().rev() {
+            *slot = suffixes[current as usize];
+            current = prefixes[current as usize];
+        }
+
+        previous = Some(code);
+    }
+
+    Ok(indices)
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    buffered_bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            buffered_bits: 0,
+        }
+    }
+
+    fn write(&mut self, code: u16, bits: u8) {
+        self.buffer |= (code as u32) << self.buffered_bits;
+        self.buffered_bits += bits;
+        while self.buffered_bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.buffered_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.buffered_bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+// Lets a run of pixels continue through a pixel whose color is close to, but not the same as,
+// the color the dictionary would continue it with, as gifsicle's --lossy does. Longer runs mean
+// fewer codes, at the cost of pixels coming out up to `tolerance` off their color.
+pub struct Lossy<'a> {
+    pub colors: &'a [[u8; 3]],
+    pub transparent_index: Option<u8>,
+    // The largest distance in RGB a pixel may be moved by.
+    pub tolerance: u32,
+}
+
+impl Lossy<'_> {
+    // The closest substitute for `index` among the codes that continue the current run.
+    fn substitute(&self, index: u8, continuations: &[(u8, u16)]) -> Option<u16> {
+        let color = self.color(index)?;
+        let limit = self.tolerance * self.tolerance;
+        continuations
+            .iter()
+            .filter_map(|&(candidate, code)| {
+                let candidate = self.color(candidate)?;
+                let distance: u32 = (0..3)
+                    .map(|channel| {
+                        let difference = candidate[channel] as i32 - color[channel] as i32;
+                        (difference * difference) as u32
+                    })
+                    .sum();
+                (distance <= limit).then_some((distance, code))
+            })
+            .min()
+            .map(|(_, code)| code)
+    }
+
+    // Transparent pixels are never substituted, nor used as a substitute.
+    fn color(&self, index: u8) -> Option<[u8; 3]> {
+        if Some(index) == self.transparent_index {
+            return None;
+        }
+        self.colors.get(index as usize).copied()
+    }
+}
+
+// Compresses color indices into GIF flavoured LZW data. The stream starts with a clear code, and
+// the table is cleared again whenever it fills up, so it decodes with any spec-compliant decoder.
+pub fn encode(lzw_minimum_code_size: u8, indices: &[u8]) -> Vec<u8> {
+    encode_with(lzw_minimum_code_size, indices, None)
+}
+
+pub fn encode_lossy(lzw_minimum_code_size: u8, indices: &[u8], lossy: &Lossy) -> Vec<u8> {
+    encode_with(lzw_minimum_code_size, indices, Some(lossy))
+}
+
+fn encode_with(lzw_minimum_code_size: u8, indices: &[u8], lossy: Option<&Lossy>) -> Vec<u8> {
+    debug_assert!((2..=8).contains(&lzw_minimum_code_size));
+
+    let clear_code = 1u16 << lzw_minimum_code_size;
+    let end_code = clear_code + 1;
+
+    let mut writer = BitWriter::new();
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::with_capacity(MAX_CODES);
+    // The index and code of every dictionary entry extending each code, only kept when lossy.
+    let mut continuations: Vec<Vec<(u8, u16)>> = match lossy {
+        Some(_) => vec![Vec::new(); MAX_CODES],
+        None => Vec::new(),
+    };
+    let mut code_size = lzw_minimum_code_size + 1;
+    let mut next_code = end_code + 1;
+
+    writer.write(clear_code, code_size);
+
+    let mut pixels = indices.iter();
+    let mut current = match pixels.next() {
+        Some(&index) => index as u16,
+        None => {
+            writer.write(end_code, code_size);
+            return writer.finish();
+        }
+    };
+
+    for &index in pixels {
+        debug_assert!((index as u16) < clear_code);
+        if let Some(&code) = dictionary.get(&(current, index)) {
+            current = code;
+            continue;
+        }
+        if let Some(code) =
+            lossy.and_then(|lossy| lossy.substitute(index, &continuations[current as usize]))
+        {
+            current = code;
+            continue;
+        }
+
+        writer.write(current, code_size);
+        if (next_code as usize) < MAX_CODES {
+            dictionary.insert((current, index), next_code);
+            if lossy.is_some() {
+                continuations[current as usize].push((index, next_code));
+            }
+            next_code += 1;
+            if next_code > 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+        } else {
+            writer.write(clear_code, code_size);
+            dictionary.clear();
+            for continuation in continuations.iter_mut() {
+                continuation.clear();
+            }
+            code_size = lzw_minimum_code_size + 1;
+            next_code = end_code + 1;
+        }
+        current = index as u16;
+    }
+
+    writer.write(current, code_size);
+    // The decoder adds one more table entry when it reads the last code, and may widen its codes
+    // before reading end-of-information.
+    if next_code == 1 << code_size && code_size < MAX_CODE_SIZE {
+        code_size += 1;
+    }
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Indices below 2^code_size from a fixed xorshift sequence, so runs stay short and the
+    // table fills quickly.
+    fn noise(code_size: u8, length: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491u32;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state % (1 << code_size)) as u8
+            })
+            .collect()
+    }
+
+    // Long runs of each index, which build long dictionary entries.
+    fn runs(code_size: u8, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| ((i / 97) % (1 << code_size)) as u8)
+            .collect()
+    }
+
+    fn assert_round_trip(code_size: u8, indices: &[u8]) {
+        let data = encode(code_size, indices);
+        assert_eq!(decode(code_size, &data).unwrap(), indices);
+    }
+
+    #[test]
+    fn round_trips_short_inputs() {
+        for code_size in 2..=8 {
+            assert_round_trip(code_size, &[]);
+            assert_round_trip(code_size, &[0]);
+            assert_round_trip(code_size, &[((1u16 << code_size) - 1) as u8; 3]);
+            assert_round_trip(code_size, &noise(code_size, 100));
+        }
+    }
+
+    #[test]
+    fn round_trips_inputs_that_fill_the_table() {
+        for code_size in 2..=8 {
+            let indices = noise(code_size, 200_000);
+            let data = encode(code_size, &indices);
+            // No code is wider than 12 bits, so this many bytes hold more codes than the table
+            // has entries, and the encoder must have cleared it.
+            assert!(data.len() * 8 > MAX_CODES * MAX_CODE_SIZE as usize);
+            assert_eq!(decode(code_size, &data).unwrap(), indices);
+
+            assert_round_trip(code_size, &runs(code_size, 200_000));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        // A clear code, then code 7, which is not a color index, with 3 bit codes.
+        assert!(matches!(
+            decode(2, &[0b0011_1100, 0b0000_0000]),
+            Err(GifError::InvalidLzw { .. })
+        ));
+        assert!(matches!(decode(9, &[0]), Err(GifError::InvalidLzw { .. })));
+    }
+
+    #[test]
+    fn lossy_with_no_tolerance_round_trips() {
+        let colors: Vec<[u8; 3]> = (0..=255).map(|i| [i, i, i]).collect();
+        let lossy = Lossy {
+            colors: &colors,
+            transparent_index: None,
+            tolerance: 0,
+        };
+        for code_size in 2..=8 {
+            let indices = noise(code_size, 50_000);
+            let data = encode_lossy(code_size, &indices, &lossy);
+            assert_eq!(decode(code_size, &data).unwrap(), indices);
+        }
+    }
+}
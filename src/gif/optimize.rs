@@ -0,0 +1,861 @@
+use std::collections::{HashMap, HashSet};
+
+use super::builder::{
+    bounding_box, clear_area, color_table_size, needs_clearing, padded_colors, Rectangle,
+};
+use super::compositor::{
+    DISPOSAL_NONE, DISPOSAL_RESTORE_BACKGROUND, DISPOSAL_RESTORE_PREVIOUS, DISPOSAL_UNSPECIFIED,
+};
+use super::lzw::{self, Lossy};
+use super::{
+    Color, GCEPackedFields, GIFImage, GifError, GlobalColorTable, GraphicControlExtension,
+    ImageData, LocalColorTable, GIF,
+};
+
+// A frame's pixels as the colors they show, `None` where nothing is drawn.
+struct FramePixels {
+    pixels: Vec<Option<[u8; 3]>>,
+    counts: HashMap<[u8; 3], u64>,
+    transparent: bool,
+    uses_global: bool,
+}
+
+// A compacted color table. The transparent index, when needed, comes right after the colors.
+struct Table {
+    colors: Vec<[u8; 3]>,
+    positions: HashMap<[u8; 3], u8>,
+    transparent: bool,
+}
+
+impl Table {
+    // Colors are ordered from the most to the least used, as the sort flag promises.
+    fn new(counts: &HashMap<[u8; 3], u64>, transparent: bool) -> Table {
+        let mut colors: Vec<[u8; 3]> = counts.keys().copied().collect();
+        colors.sort_by_key(|color| (std::cmp::Reverse(counts[color]), *color));
+        let positions = colors
+            .iter()
+            .enumerate()
+            .map(|(index, &color)| (color, index as u8))
+            .collect();
+        Table {
+            colors,
+            positions,
+            transparent,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.colors.len() + self.transparent as usize
+    }
+
+    fn transparent_index(&self) -> u8 {
+        self.colors.len() as u8
+    }
+
+    fn size(&self) -> u8 {
+        color_table_size(self.len())
+    }
+
+    fn padded_colors(&self) -> Vec<Color> {
+        let colors: Vec<Color> = self
+            .colors
+            .iter()
+            .map(|&[red, green, blue]| Color { red, green, blue })
+            .collect();
+        padded_colors(&colors, self.size())
+    }
+
+    fn covers(&self, frame: &FramePixels) -> bool {
+        (self.transparent || !frame.transparent)
+            && frame
+                .counts
+                .keys()
+                .all(|color| self.positions.contains_key(color))
+    }
+}
+
+fn add_counts(total: &mut HashMap<[u8; 3], u64>, counts: &HashMap<[u8; 3], u64>) {
+    for (&color, &count) in counts {
+        *total.entry(color).or_insert(0) += count;
+    }
+}
+
+fn frame_pixels(
+    image: &GIFImage,
+    global_color_table: Option<&GlobalColorTable>,
+) -> Result<FramePixels, GifError> {
+    let colors = image.color_table(global_color_table)?;
+    let transparent_index = image.transparent_index();
+
+    let mut counts = HashMap::new();
+    let mut transparent = false;
+    let pixels = image
+        .indices()?
+        .into_iter()
+        .map(|index| {
+            // Indices past the end of the table are not drawn either, so they become transparent.
+            let color = colors
+                .get(index as usize)
+                .filter(|_| Some(index) != transparent_index)
+                .map(|color| [color.red, color.green, color.blue]);
+            match color {
+                Some(color) => *counts.entry(color).or_insert(0) += 1,
+                None => transparent = true,
+            }
+            color
+        })
+        .collect();
+
+    Ok(FramePixels {
+        pixels,
+        counts,
+        transparent,
+        uses_global: image.local_color_table.is_none(),
+    })
+}
+
+// The frame's graphic control extension, added with default values if it has none.
+fn set_transparent_index(image: &mut GIFImage, transparent_index: Option<u8>) {
+    match transparent_index {
+        Some(_) => image
+            .graphic_control_extension_or_default()
+            .set_transparent_index(transparent_index),
+        None => {
+            if let Some(graphic_control_extension) = image.graphic_control_extension_mut() {
+                graphic_control_extension.set_transparent_index(None);
+            }
+        }
+    }
+}
+
+// Puts rows back in the order an interlaced frame stores them.
+fn interlace(indices: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut interlaced = Vec::with_capacity(indices.len());
+    for (start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)] {
+        for y in (start..height).step_by(step) {
+            interlaced.extend_from_slice(&indices[y * width..(y + 1) * width]);
+        }
+    }
+    interlaced
+}
+
+fn remap(
+    image: &mut GIFImage,
+    frame: &FramePixels,
+    table: &Table,
+    local: bool,
+) -> Result<(), GifError> {
+    let indices: Vec<u8> = frame
+        .pixels
+        .iter()
+        .map(|pixel| match pixel {
+            Some(color) => table.positions[color],
+            None => table.transparent_index(),
+        })
+        .collect();
+
+    set_transparent_index(image, frame.transparent.then(|| table.transparent_index()));
+
+    let packed_fields = &mut image.image_descriptor.packed_fields;
+    packed_fields.local_color_table_flag = local;
+    packed_fields.sort_flag = local;
+    packed_fields.size_of_local_color_table = if local { table.size() } else { 0 };
+    image.local_color_table = if local {
+        Some(LocalColorTable {
+            colors: table.padded_colors(),
+        })
+    } else {
+        None
+    };
+
+    // Frames whose indices and code size come out the same keep their compressed data as is.
+    let lzw_minimum_code_size = ImageData::minimum_code_size(table.len());
+    if lzw_minimum_code_size != image.image_data.lzw_minimum_code_size
+        || image.indices()? != indices
+    {
+        let descriptor = &image.image_descriptor;
+        let indices = if descriptor.packed_fields.interlace_flag {
+            interlace(
+                &indices,
+                descriptor.width as usize,
+                descriptor.height as usize,
+            )
+        } else {
+            indices
+        };
+        image.image_data = ImageData::from_indices(lzw_minimum_code_size, &indices);
+    }
+    Ok(())
+}
+
+// The color table a frame was stored with. An optimized frame keeps drawing from it whenever it
+// holds every color the frame needs.
+struct SourceTable {
+    colors: Vec<[u8; 3]>,
+    transparent_index: Option<u8>,
+}
+
+fn source_table(
+    image: &GIFImage,
+    global_color_table: Option<&GlobalColorTable>,
+) -> Result<SourceTable, GifError> {
+    Ok(SourceTable {
+        colors: image
+            .color_table(global_color_table)?
+            .iter()
+            .map(|color| [color.red, color.green, color.blue])
+            .collect(),
+        transparent_index: image.transparent_index(),
+    })
+}
+
+// A frame cut down to a rectangle of the canvas, with the pixels that already show the right
+// color made transparent.
+struct DeltaFrame {
+    area: Rectangle,
+    indices: Vec<u8>,
+    // A table of just the colors drawn, when the source table does not hold them all or has no
+    // index to spare for transparency.
+    local_colors: Option<Vec<[u8; 3]>>,
+    transparent_index: Option<u8>,
+    lzw_minimum_code_size: u8,
+    compressed_size: usize,
+    // The frame is written as it was stored, and only its disposal method changes.
+    stored: bool,
+}
+
+// Draws `target` over `canvas`, inside `area` or else the smallest rectangle covering every
+// change. `None` when the frame's colors do not fit in a table.
+fn delta_frame(
+    target: &[Option<[u8; 3]>],
+    canvas: &[Option<[u8; 3]>],
+    width: usize,
+    area: Option<Rectangle>,
+    table: &SourceTable,
+) -> Option<DeltaFrame> {
+    let area = area.unwrap_or_else(|| {
+        let changed: Vec<bool> = canvas
+            .iter()
+            .zip(target)
+            .map(|(canvas, target)| canvas != target)
+            .collect();
+        bounding_box(width, &changed).unwrap_or(Rectangle {
+            left: 0,
+            top: 0,
+            width: 1,
+            height: 1,
+        })
+    });
+
+    let mut drawn = Vec::with_capacity(area.width * area.height);
+    for y in area.top..area.top + area.height {
+        for i in y * width + area.left..y * width + area.left + area.width {
+            drawn.push(if canvas[i] == target[i] {
+                None
+            } else {
+                target[i]
+            });
+        }
+    }
+    let needed: HashSet<[u8; 3]> = drawn.iter().flatten().copied().collect();
+    let needs_transparency = drawn.iter().any(Option::is_none);
+
+    let mut positions: HashMap<[u8; 3], u8> = HashMap::new();
+    for (index, &color) in table.colors.iter().enumerate().rev() {
+        positions.insert(color, index as u8);
+    }
+    let mut local_colors = None;
+    let mut transparent_index = None;
+    let mut color_count = table.colors.len();
+    let used: HashSet<u8> = needed
+        .iter()
+        .filter_map(|color| positions.get(color).copied())
+        .collect();
+    if needs_transparency {
+        // The source's transparent index may point past its table, where the frame's codes
+        // cannot reach, so it is only kept when it fits the code size.
+        let code_limit = 1usize << ImageData::minimum_code_size(color_count);
+        transparent_index = table
+            .transparent_index
+            .filter(|&index| (index as usize) < code_limit)
+            .into_iter()
+            .chain((0..table.colors.len()).map(|index| index as u8))
+            .find(|index| !used.contains(index));
+    }
+    if used.len() < needed.len() || (needs_transparency && transparent_index.is_none()) {
+        if needed.len() + needs_transparency as usize > 256 {
+            return None;
+        }
+        let mut colors: Vec<[u8; 3]> = needed.into_iter().collect();
+        colors.sort();
+        positions = colors
+            .iter()
+            .enumerate()
+            .map(|(index, &color)| (color, index as u8))
+            .collect();
+        transparent_index = needs_transparency.then_some(colors.len() as u8);
+        color_count = colors.len() + needs_transparency as usize;
+        local_colors = Some(colors);
+    }
+
+    let indices: Vec<u8> = drawn
+        .iter()
+        .map(|pixel| match pixel {
+            Some(color) => positions[color],
+            None => transparent_index.unwrap_or(0),
+        })
+        .collect();
+    let lzw_minimum_code_size = ImageData::minimum_code_size(color_count);
+    Some(DeltaFrame {
+        area,
+        compressed_size: lzw::encode(lzw_minimum_code_size, &indices).len(),
+        indices,
+        local_colors,
+        transparent_index,
+        lzw_minimum_code_size,
+        stored: false,
+    })
+}
+
+// The frame as it was stored, if drawing it over `canvas` shows `target`.
+fn stored_frame(
+    image: &GIFImage,
+    indices: &[u8],
+    table: &SourceTable,
+    canvas: &[Option<[u8; 3]>],
+    target: &[Option<[u8; 3]>],
+    width: usize,
+) -> Option<DeltaFrame> {
+    let height = canvas.len() / width;
+    let descriptor = &image.image_descriptor;
+    let (left, top) = (
+        descriptor.left_position as usize,
+        descriptor.top_position as usize,
+    );
+    let frame_width = descriptor.width as usize;
+    let right = (left + frame_width).min(width);
+    let bottom = (top + descriptor.height as usize).min(height);
+
+    let mut drawn = canvas.to_vec();
+    for y in top..bottom {
+        for x in left..right {
+            let index = indices[(y - top) * frame_width + x - left];
+            if Some(index) == table.transparent_index {
+                continue;
+            }
+            if let Some(&color) = table.colors.get(index as usize) {
+                drawn[y * width + x] = Some(color);
+            }
+        }
+    }
+    if drawn != target {
+        return None;
+    }
+
+    Some(DeltaFrame {
+        area: Rectangle {
+            left: left.min(width),
+            top: top.min(height),
+            width: right.saturating_sub(left),
+            height: bottom.saturating_sub(top),
+        },
+        indices: Vec::new(),
+        local_colors: None,
+        transparent_index: table.transparent_index,
+        lzw_minimum_code_size: image.image_data.lzw_minimum_code_size,
+        compressed_size: image.image_data.data().len(),
+        stored: true,
+    })
+}
+
+struct FramePlan {
+    // The canvas the frame is drawn onto.
+    canvas: Vec<Option<[u8; 3]>>,
+    delta: DeltaFrame,
+    disposal_method: u8,
+}
+
+// The canvas left for the next frame once `plan`'s frame, which showed `shown`, is disposed of.
+fn disposed(
+    plan: &FramePlan,
+    shown: &[Option<[u8; 3]>],
+    width: usize,
+    disposal_method: u8,
+) -> Vec<Option<[u8; 3]>> {
+    match disposal_method {
+        DISPOSAL_RESTORE_BACKGROUND => {
+            let mut canvas = shown.to_vec();
+            clear_area(&mut canvas, width, plan.delta.area);
+            canvas
+        }
+        DISPOSAL_RESTORE_PREVIOUS => plan.canvas.clone(),
+        _ => shown.to_vec(),
+    }
+}
+
+// The stored frame unless the delta frame compresses smaller.
+fn smallest(stored: Option<DeltaFrame>, delta: Option<DeltaFrame>) -> Option<DeltaFrame> {
+    match (stored, delta) {
+        (Some(stored), Some(delta)) if delta.compressed_size < stored.compressed_size => {
+            Some(delta)
+        }
+        (stored, delta) => stored.or(delta),
+    }
+}
+
+fn union(a: Rectangle, b: Rectangle) -> Rectangle {
+    let left = a.left.min(b.left);
+    let top = a.top.min(b.top);
+    Rectangle {
+        left,
+        top,
+        width: (a.left + a.width).max(b.left + b.width) - left,
+        height: (a.top + a.height).max(b.top + b.height) - top,
+    }
+}
+
+impl GIF {
+    // Shrinks the color tables to the colors frames actually draw. Unused and duplicate entries
+    // are dropped, tables and code sizes are made as small as their colors allow, and local
+    // tables are folded into the global one when all colors fit in a single table. Frames look
+    // the same as before.
+    pub fn optimize_palettes(&self) -> Result<GIF, GifError> {
+        let frames = self
+            .images
+            .iter()
+            .map(|image| frame_pixels(image, self.global_color_table.as_ref()))
+            .collect::<Result<Vec<FramePixels>, GifError>>()?;
+
+        let mut all_counts = HashMap::new();
+        for frame in &frames {
+            add_counts(&mut all_counts, &frame.counts);
+        }
+        let any_transparent = frames.iter().any(|frame| frame.transparent);
+
+        // Which frames draw from the global table.
+        let on_global: Vec<bool> = if all_counts.len() + any_transparent as usize <= 256 {
+            vec![true; frames.len()]
+        } else {
+            let mut on_global: Vec<bool> = frames.iter().map(|frame| frame.uses_global).collect();
+            let global_colors: HashSet<[u8; 3]> = frames
+                .iter()
+                .filter(|frame| frame.uses_global)
+                .flat_map(|frame| frame.counts.keys().copied())
+                .collect();
+            // A global table full of colors has no slot left for a transparent index, so frames
+            // that need one get a local table instead.
+            if global_colors.len() == 256 {
+                for (on_global, frame) in on_global.iter_mut().zip(&frames) {
+                    *on_global &= !frame.transparent;
+                }
+            }
+            on_global
+        };
+
+        let mut global_counts = HashMap::new();
+        let mut global_transparent = false;
+        for (frame, _) in frames
+            .iter()
+            .zip(&on_global)
+            .filter(|(_, &on_global)| on_global)
+        {
+            add_counts(&mut global_counts, &frame.counts);
+            global_transparent |= frame.transparent;
+        }
+        let has_global = on_global.iter().any(|&on_global| on_global);
+        let global = Table::new(&global_counts, global_transparent);
+
+        let mut optimized = self.clone();
+        for ((image, frame), &on_global) in optimized.images.iter_mut().zip(&frames).zip(&on_global)
+        {
+            // Frames with a local table of their own move to the global one when it already
+            // holds all of their colors.
+            let table = if on_global || (has_global && global.covers(frame)) {
+                None
+            } else {
+                Some(Table::new(&frame.counts, frame.transparent))
+            };
+            match &table {
+                Some(table) => remap(image, frame, table, true)?,
+                None => remap(image, frame, &global, false)?,
+            }
+        }
+
+        let background = self.global_color_table.as_ref().and_then(|table| {
+            table
+                .colors
+                .get(self.logical_screen_descriptor.background_color_index as usize)
+                .map(|color| [color.red, color.green, color.blue])
+        });
+        let screen = &mut optimized.logical_screen_descriptor;
+        screen.background_color_index = background
+            .and_then(|color| global.positions.get(&color).copied())
+            .unwrap_or(0);
+        if has_global {
+            screen.packed_fields.global_color_table_flag = true;
+            screen.packed_fields.sort_flag = true;
+            screen.packed_fields.size_of_global_color_table = global.size();
+            optimized.global_color_table = Some(GlobalColorTable {
+                colors: global.padded_colors(),
+            });
+        } else {
+            screen.packed_fields.global_color_table_flag = false;
+            screen.packed_fields.sort_flag = false;
+            screen.packed_fields.size_of_global_color_table = 0;
+            optimized.global_color_table = None;
+        }
+        Ok(optimized)
+    }
+    // Stores each frame as only the rectangle that changed since the canvas it is drawn onto,
+    // with unchanged pixels inside it made transparent. Every frame tries each way of disposing
+    // of the frame before it and keeps whichever compresses smallest. Frames keep their color
+    // tables where they can, so the animation looks exactly the same. Gifs that cannot be
+    // optimized without losing colors are returned as they are.
+    pub fn optimize_frames(&self) -> Result<GIF, GifError> {
+        let width = self.logical_screen_descriptor.width as usize;
+        let height = self.logical_screen_descriptor.height as usize;
+        if width == 0 || height == 0 {
+            return Ok(self.clone());
+        }
+        let targets: Vec<Vec<Option<[u8; 3]>>> = self
+            .frames()?
+            .iter()
+            .map(|frame| {
+                frame
+                    .pixels()
+                    .map(|pixel| (pixel[3] != 0).then_some([pixel[0], pixel[1], pixel[2]]))
+                    .collect()
+            })
+            .collect();
+        let tables = self
+            .images
+            .iter()
+            .map(|image| source_table(image, self.global_color_table.as_ref()))
+            .collect::<Result<Vec<SourceTable>, GifError>>()?;
+
+        let mut plans: Vec<FramePlan> = Vec::with_capacity(targets.len());
+        for (i, target) in targets.iter().enumerate() {
+            let indices = self.images[i].indices()?;
+            let Some(previous) = plans.last_mut() else {
+                let canvas = vec![None; width * height];
+                let delta = delta_frame(target, &canvas, width, None, &tables[i]);
+                let stored = stored_frame(
+                    &self.images[i],
+                    &indices,
+                    &tables[i],
+                    &canvas,
+                    target,
+                    width,
+                );
+                let Some(delta) = smallest(stored, delta) else {
+                    return Ok(self.clone());
+                };
+                plans.push(FramePlan {
+                    canvas,
+                    delta,
+                    disposal_method: DISPOSAL_NONE,
+                });
+                continue;
+            };
+            let shown = &targets[i - 1];
+
+            // The previous frame's disposal method, and this frame drawn over what it leaves.
+            let mut best: Option<(u8, FramePlan)> = None;
+            for disposal_method in [
+                DISPOSAL_NONE,
+                DISPOSAL_RESTORE_BACKGROUND,
+                DISPOSAL_RESTORE_PREVIOUS,
+            ] {
+                let canvas = disposed(previous, shown, width, disposal_method);
+                if needs_clearing(&canvas, target) {
+                    continue;
+                }
+                let delta = delta_frame(target, &canvas, width, None, &tables[i]);
+                let stored = stored_frame(
+                    &self.images[i],
+                    &indices,
+                    &tables[i],
+                    &canvas,
+                    target,
+                    width,
+                );
+                let Some(delta) = smallest(stored, delta) else {
+                    continue;
+                };
+                if best
+                    .as_ref()
+                    .is_none_or(|(_, best)| delta.compressed_size < best.delta.compressed_size)
+                {
+                    let plan = FramePlan {
+                        canvas,
+                        delta,
+                        disposal_method: DISPOSAL_NONE,
+                    };
+                    best = Some((disposal_method, plan));
+                }
+            }
+
+            let (disposal_method, plan) = match best {
+                Some(best) => best,
+                None => {
+                    // The frame needs pixels cleared outside the previous frame's rectangle, so
+                    // that rectangle is widened to cover them and restored to the background.
+                    let to_clear: Vec<bool> = shown
+                        .iter()
+                        .zip(target)
+                        .map(|(shown, target)| shown.is_some() && target.is_none())
+                        .collect();
+                    let area = match bounding_box(width, &to_clear) {
+                        Some(area) => union(previous.delta.area, area),
+                        None => previous.delta.area,
+                    };
+                    let Some(widened) =
+                        delta_frame(shown, &previous.canvas, width, Some(area), &tables[i - 1])
+                    else {
+                        return Ok(self.clone());
+                    };
+                    previous.delta = widened;
+                    let canvas = disposed(previous, shown, width, DISPOSAL_RESTORE_BACKGROUND);
+                    let Some(delta) = delta_frame(target, &canvas, width, None, &tables[i]) else {
+                        return Ok(self.clone());
+                    };
+                    let plan = FramePlan {
+                        canvas,
+                        delta,
+                        disposal_method: DISPOSAL_NONE,
+                    };
+                    (DISPOSAL_RESTORE_BACKGROUND, plan)
+                }
+            };
+            previous.disposal_method = disposal_method;
+            plans.push(plan);
+        }
+
+        let mut optimized = self.clone();
+        for (image, plan) in optimized.images.iter_mut().zip(&plans) {
+            image
+                .graphic_control_extension_or_default()
+                .packed_fields
+                .disposal_method = plan.disposal_method;
+            let delta = &plan.delta;
+            if delta.stored {
+                continue;
+            }
+            let descriptor = &mut image.image_descriptor;
+            descriptor.left_position = delta.area.left as u16;
+            descriptor.top_position = delta.area.top as u16;
+            descriptor.width = delta.area.width as u16;
+            descriptor.height = delta.area.height as u16;
+            descriptor.packed_fields.interlace_flag = false;
+            if let Some(colors) = &delta.local_colors {
+                let size =
+                    color_table_size(colors.len() + delta.transparent_index.is_some() as usize);
+                let colors: Vec<Color> = colors
+                    .iter()
+                    .map(|&[red, green, blue]| Color { red, green, blue })
+                    .collect();
+                descriptor.packed_fields.local_color_table_flag = true;
+                descriptor.packed_fields.sort_flag = false;
+                descriptor.packed_fields.size_of_local_color_table = size;
+                image.local_color_table = Some(LocalColorTable {
+                    colors: padded_colors(&colors, size),
+                });
+            }
+            image.image_data = ImageData::from_indices(delta.lzw_minimum_code_size, &delta.indices);
+            set_transparent_index(image, delta.transparent_index);
+        }
+        Ok(optimized)
+    }
+
+    // Re-compresses every frame with lossy LZW, which lets runs of pixels take on colors up to
+    // `tolerance` away in RGB, from 0 to 441, so they compress into fewer codes. Around 10 is
+    // hard to notice, 30 and up shows as noise. Frames that would not get smaller are kept as
+    // they are.
+    pub fn compress_lossy(&self, tolerance: u32) -> Result<GIF, GifError> {
+        let mut compressed = self.clone();
+        if tolerance == 0 {
+            return Ok(compressed);
+        }
+        for image in &mut compressed.images {
+            let table = source_table(image, self.global_color_table.as_ref())?;
+            let descriptor = &image.image_descriptor;
+            let mut indices = image.indices()?;
+            if descriptor.packed_fields.interlace_flag {
+                indices = interlace(
+                    &indices,
+                    descriptor.width as usize,
+                    descriptor.height as usize,
+                );
+            }
+
+            let lzw_minimum_code_size = image.image_data.lzw_minimum_code_size;
+            let lossy = Lossy {
+                colors: &table.colors,
+                transparent_index: table.transparent_index,
+                tolerance,
+            };
+            let data = lzw::encode_lossy(lzw_minimum_code_size, &indices, &lossy);
+            if data.len() < image.image_data.data().len() {
+                image.image_data = ImageData::from_data(lzw_minimum_code_size, &data);
+            }
+        }
+        Ok(compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::super::EncodeOptions;
+    use super::*;
+
+    fn bundled(name: &str) -> GIF {
+        GIF::from_file(&format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    // A square moving over a banded gradient with a transparent hole, which moves the other way
+    // when `hole_moves` is set. Frames are stored whole, so there is something to optimize.
+    fn animation(hole_moves: bool) -> GIF {
+        let frames: Vec<RgbaImage> = (0..8)
+            .map(|step| {
+                let hole_x = if hole_moves { 48 - 4 * step } else { 48 };
+                RgbaImage::from_fn(64, 64, |x, y| {
+                    let (x, y) = (x as i32, y as i32);
+                    if (x - hole_x).abs() < 5 && (y - 40).abs() < 5 {
+                        Rgba([0, 0, 0, 0])
+                    } else if (4 * step..4 * step + 8).contains(&x) && (10..18).contains(&y) {
+                        Rgba([220, 40, 40, 255])
+                    } else {
+                        Rgba([(x / 8 * 30) as u8, (y / 8 * 30) as u8, 128, 255])
+                    }
+                })
+            })
+            .collect();
+        let delays: Vec<u16> = (0..8).map(|step| 4 + step).collect();
+        let options = EncodeOptions {
+            optimize: false,
+            ..EncodeOptions::default()
+        };
+        GIF::from_frames(&frames, &delays, &options).unwrap()
+    }
+
+    fn assert_looks_the_same(before: &GIF, after: &GIF) {
+        assert_eq!(after.delays(), before.delays());
+        assert!(after.frames().unwrap() == before.frames().unwrap());
+    }
+
+    #[test]
+    fn optimized_frames_look_the_same() {
+        for gif in [bundled("loading.gif"), animation(false), animation(true)] {
+            assert_looks_the_same(&gif, &gif.optimize_frames().unwrap());
+        }
+    }
+
+    #[test]
+    fn optimized_palettes_look_the_same() {
+        for gif in [bundled("loading.gif"), animation(false), animation(true)] {
+            assert_looks_the_same(&gif, &gif.optimize_palettes().unwrap());
+        }
+    }
+
+    #[test]
+    fn optimizing_frames_shrinks_full_frames() {
+        let gif = animation(false);
+        let optimized = gif.optimize_frames().unwrap();
+        assert!(optimized.to_bytes().unwrap().len() < gif.to_bytes().unwrap().len());
+    }
+
+    // A smooth gradient with a little noise, so neighbouring pixels take many close colors.
+    fn noisy_gradient() -> GIF {
+        let mut state = 0x2545_F491u32;
+        let frame = RgbaImage::from_fn(96, 64, |x, y| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = state % 9;
+            Rgba([
+                (x * 2 + noise) as u8,
+                (y * 3 + noise) as u8,
+                (160 - x + y) as u8,
+                255,
+            ])
+        });
+        GIF::from_frames(&[frame], &[10], &EncodeOptions::default()).unwrap()
+    }
+
+    fn image_data_size(gif: &GIF) -> usize {
+        gif.images
+            .iter()
+            .map(|image| image.image_data.data().len())
+            .sum()
+    }
+
+    #[test]
+    fn lossy_compression_shrinks_within_its_tolerance() {
+        const TOLERANCE: u32 = 12;
+        let gif = noisy_gradient();
+        let compressed = gif.compress_lossy(TOLERANCE).unwrap();
+        assert!(image_data_size(&compressed) < image_data_size(&gif));
+
+        let mut squared_error = 0u64;
+        let mut samples = 0u64;
+        for (source, lossy) in gif.frames().unwrap().iter().zip(compressed.frames().unwrap()) {
+            for (source, lossy) in source.pixels().zip(lossy.pixels()) {
+                assert_eq!(source[3], lossy[3]);
+                let distance: u32 = (0..3)
+                    .map(|channel| (source[channel] as i32 - lossy[channel] as i32).pow(2) as u32)
+                    .sum();
+                assert!(distance <= TOLERANCE * TOLERANCE);
+                squared_error += distance as u64;
+                samples += 3;
+            }
+        }
+        let mean_squared_error = squared_error as f64 / samples as f64;
+        let psnr = 10.0 * (255.0 * 255.0 / mean_squared_error).log10();
+        // Around 43 dB at this tolerance, well past where the noise is hard to see.
+        assert!(psnr > 38.0, "PSNR {:.1} dB", psnr);
+    }
+
+    #[test]
+    fn lossy_compression_with_no_tolerance_changes_nothing() {
+        let gif = noisy_gradient();
+        assert!(gif.compress_lossy(0).unwrap().to_bytes().unwrap() == gif.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn transparent_indices_past_the_table_are_not_reused() {
+        let (red, green, blue) = (
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 255, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+        );
+        // Two pixels at opposite corners swap colors, so the changed rectangle holds unchanged
+        // pixels that must be written as transparent.
+        let frame = |first, last| {
+            RgbaImage::from_fn(8, 8, |x, y| match (x, y) {
+                (0, 0) => first,
+                (7, 7) => last,
+                _ => red,
+            })
+        };
+        let options = EncodeOptions {
+            optimize: false,
+            ..EncodeOptions::default()
+        };
+        let mut gif =
+            GIF::from_frames(&[frame(green, blue), frame(blue, green)], &[10, 10], &options)
+                .unwrap();
+        assert_eq!(gif.global_color_table.as_ref().unwrap().colors.len(), 4);
+        for image in &mut gif.images {
+            image
+                .graphic_control_extension_mut()
+                .unwrap()
+                .set_transparent_index(Some(200));
+        }
+
+        let optimized = gif.optimize_frames().unwrap();
+        let decoded = GIF::from_bytes(&optimized.to_bytes().unwrap()).unwrap();
+        assert_looks_the_same(&gif, &decoded);
+    }
+}
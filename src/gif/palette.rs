@@ -0,0 +1,93 @@
+use super::Color;
+
+// Colors are bucketed by the top 5 bits of each channel.
+const CELL_BITS: u32 = 5;
+const CELLS_PER_CHANNEL: usize = 1 << CELL_BITS;
+const CELL_SIZE: i32 = 1 << (8 - CELL_BITS);
+
+// Green differences are easier to see than red ones, and red ones than blue ones.
+const CHANNEL_WEIGHTS: [i32; 3] = [2, 4, 3];
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> i32 {
+    (0..3)
+        .map(|channel| {
+            let difference = a[channel] as i32 - b[channel] as i32;
+            CHANNEL_WEIGHTS[channel] * difference * difference
+        })
+        .sum()
+}
+
+// Finds the nearest palette color to any color. The palette entries that can be nearest to some
+// color in a cell of the 32x32x32 RGB cube are worked out the first time a color lands in that
+// cell, so a lookup only compares against a handful of entries.
+pub struct PaletteIndex {
+    palette: Vec<[u8; 3]>,
+    cells: Vec<Option<Vec<u8>>>,
+}
+
+impl PaletteIndex {
+    pub fn new(colors: &[Color]) -> PaletteIndex {
+        let palette: Vec<[u8; 3]> = colors
+            .iter()
+            .map(|color| [color.red, color.green, color.blue])
+            .collect();
+        PaletteIndex::from_rgb(&palette)
+    }
+
+    // Only the first 256 entries can be indexed.
+    pub fn from_rgb(palette: &[[u8; 3]]) -> PaletteIndex {
+        PaletteIndex {
+            palette: palette.iter().take(256).copied().collect(),
+            cells: vec![None; CELLS_PER_CHANNEL * CELLS_PER_CHANNEL * CELLS_PER_CHANNEL],
+        }
+    }
+
+    // The index of the nearest palette color, the first one if several are as near. 0 for an
+    // empty palette.
+    pub fn nearest(&mut self, color: [u8; 3]) -> u8 {
+        let cell = color.iter().fold(0, |cell, &channel| {
+            (cell << CELL_BITS) | (channel >> (8 - CELL_BITS)) as usize
+        });
+        let palette = &self.palette;
+        let candidates = self.cells[cell].get_or_insert_with(|| candidates(palette, color));
+        candidates
+            .iter()
+            .min_by_key(|&&index| distance(palette[index as usize], color))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+// The palette entries that could be nearest to some color in the cell holding `color`: those
+// whose closest possible distance to the cell is within the smallest farthest distance of any
+// entry.
+fn candidates(palette: &[[u8; 3]], color: [u8; 3]) -> Vec<u8> {
+    let low = color.map(|channel| (channel as i32 / CELL_SIZE) * CELL_SIZE);
+    let bounds: Vec<(i32, i32)> = palette
+        .iter()
+        .map(|entry| {
+            let mut nearest = 0;
+            let mut farthest = 0;
+            for channel in 0..3 {
+                let value = entry[channel] as i32;
+                let (low, high) = (low[channel], low[channel] + CELL_SIZE - 1);
+                let near = (low - value).max(value - high).max(0);
+                let far = (value - low).abs().max((value - high).abs());
+                nearest += CHANNEL_WEIGHTS[channel] * near * near;
+                farthest += CHANNEL_WEIGHTS[channel] * far * far;
+            }
+            (nearest, farthest)
+        })
+        .collect();
+    let limit = bounds
+        .iter()
+        .map(|&(_, farthest)| farthest)
+        .min()
+        .unwrap_or(0);
+    bounds
+        .iter()
+        .enumerate()
+        .filter(|(_, &(nearest, _))| nearest <= limit)
+        .map(|(index, _)| index as u8)
+        .collect()
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+
+use image::RgbaImage;
+
+use super::Color;
+
+// NeuQuant trains on at most this many pixels, drawn from the histogram in proportion to how
+// often each color is used.
+const MAX_TRAINING_PIXELS: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Quantizer {
+    // Repeatedly splits the box of colors covering the most pixels at the median of its widest
+    // channel. Fast, and good for flat colors.
+    #[default]
+    MedianCut,
+    // Builds an octree of the colors and merges the least used branches. Fast, keeps rare but
+    // distinct colors a little better than median cut.
+    Octree,
+    // Anthony Dekker's NeuQuant neural network. The slowest. With a sample factor of 1 it beats
+    // median cut on photos and gradients reduced to 64 colors or so, but not at 256 colors, and
+    // it does poorly on images of a few hundred pixels. Every `sample_factor`-th training pixel
+    // is learned, 1 is the best quality and 30 the fastest.
+    NeuQuant {
+        sample_factor: u32,
+    },
+}
+
+// How many pixels use each color, leaving out pixels with an alpha below the threshold.
+pub fn histogram(images: &[RgbaImage], alpha_threshold: u8) -> HashMap<[u8; 3], u32> {
+    let mut histogram = HashMap::new();
+    for image in images {
+        for pixel in image.pixels() {
+            if pixel[3] >= alpha_threshold {
+                *histogram.entry([pixel[0], pixel[1], pixel[2]]).or_insert(0) += 1;
+            }
+        }
+    }
+    histogram
+}
+
+impl Quantizer {
+    // A palette of at most `max_colors` colors for the images, which may be a single frame or
+    // every frame of an animation. Images with few enough colors get exactly their colors.
+    pub fn palette(
+        self,
+        images: &[RgbaImage],
+        max_colors: usize,
+        alpha_threshold: u8,
+    ) -> Vec<Color> {
+        self.rgb_palette(&histogram(images, alpha_threshold), max_colors)
+            .into_iter()
+            .map(|[red, green, blue]| Color { red, green, blue })
+            .collect()
+    }
+
+    pub fn rgb_palette(self, histogram: &HashMap<[u8; 3], u32>, max_colors: usize) -> Vec<[u8; 3]> {
+        let max_colors = max_colors.clamp(1, 256);
+        // Sorted, so the same colors always give the same palette.
+        let mut entries: Vec<([u8; 3], u32)> = histogram
+            .iter()
+            .map(|(&color, &count)| (color, count))
+            .collect();
+        entries.sort();
+        if entries.len() <= max_colors {
+            return entries.into_iter().map(|(color, _)| color).collect();
+        }
+
+        let mut palette = match self {
+            Quantizer::MedianCut => median_cut(entries, max_colors),
+            Quantizer::Octree => octree(&entries, max_colors),
+            Quantizer::NeuQuant { sample_factor } => {
+                neuquant(&entries, max_colors, sample_factor.clamp(1, 30))
+            }
+        };
+        palette.sort();
+        palette.dedup();
+        palette
+    }
+}
+
+fn weighted_average(entries: &[([u8; 3], u32)]) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    let mut total = 0u64;
+    for &(color, count) in entries {
+        for channel in 0..3 {
+            sums[channel] += color[channel] as u64 * count as u64;
+        }
+        total += count as u64;
+    }
+    sums.map(|sum| ((sum + total / 2) / total.max(1)) as u8)
+}
+
+// The channel with the widest range of values in the box, and that range.
+fn widest_channel(entries: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = entries.iter().fold((255, 0), |(min, max), (color, _)| {
+                (color[channel].min(min), color[channel].max(max))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+fn median_cut(entries: Vec<([u8; 3], u32)>, max_colors: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![entries];
+    while boxes.len() < max_colors {
+        // The box to split is the one whose pixels are the furthest off its average, estimated
+        // as its pixel count times its widest range.
+        let candidate = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, entries)| entries.len() > 1)
+            .map(|(i, entries)| {
+                let pixels: u64 = entries.iter().map(|&(_, count)| count as u64).sum();
+                (i, pixels * widest_channel(entries).1 as u64)
+            })
+            .max_by_key(|&(_, priority)| priority);
+        let Some((i, _)) = candidate else {
+            break;
+        };
+
+        let mut entries = boxes.swap_remove(i);
+        let (channel, _) = widest_channel(&entries);
+        entries.sort_by_key(|&(color, _)| color[channel]);
+        let total: u64 = entries.iter().map(|&(_, count)| count as u64).sum();
+        let mut running = 0;
+        let mut split = entries.len() - 1;
+        for (j, &(_, count)) in entries.iter().enumerate() {
+            running += count as u64;
+            if running * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = split.clamp(1, entries.len() - 1);
+        let upper = entries.split_off(split);
+        boxes.push(entries);
+        boxes.push(upper);
+    }
+    boxes
+        .iter()
+        .map(|entries| weighted_average(entries))
+        .collect()
+}
+
+const OCTREE_DEPTH: usize = 8;
+
+#[derive(Default)]
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    // Pixel count and channel sums of everything below this node.
+    count: u64,
+    sums: [u64; 3],
+    leaf: bool,
+}
+
+impl OctreeNode {
+    fn add(&mut self, color: [u8; 3], count: u32) {
+        self.count += count as u64;
+        for (sum, channel) in self.sums.iter_mut().zip(color) {
+            *sum += channel as u64 * count as u64;
+        }
+    }
+}
+
+fn octree(entries: &[([u8; 3], u32)], max_colors: usize) -> Vec<[u8; 3]> {
+    let mut nodes = vec![OctreeNode::default()];
+    // Nodes with children, by depth.
+    let mut levels: Vec<Vec<usize>> = vec![Vec::new(); OCTREE_DEPTH];
+    let mut leaf_count = 0;
+
+    for &(color, count) in entries {
+        let mut node = 0;
+        for (depth, level) in levels.iter_mut().enumerate() {
+            nodes[node].add(color, count);
+            let shift = 7 - depth;
+            let branch = (((color[0] >> shift) & 1) << 2
+                | ((color[1] >> shift) & 1) << 1
+                | ((color[2] >> shift) & 1)) as usize;
+            node = match nodes[node].children[branch] {
+                Some(child) => child,
+                None => {
+                    nodes.push(OctreeNode::default());
+                    let child = nodes.len() - 1;
+                    if nodes[node].children.iter().all(Option::is_none) {
+                        level.push(node);
+                    }
+                    nodes[node].children[branch] = Some(child);
+                    child
+                }
+            };
+        }
+        nodes[node].add(color, count);
+        if !nodes[node].leaf {
+            nodes[node].leaf = true;
+            leaf_count += 1;
+        }
+    }
+
+    // The least used branches of the deepest level are folded into their parent first.
+    'reduce: for level in levels.iter_mut().rev() {
+        level.sort_by_key(|&node| nodes[node].count);
+        for &node in level.iter() {
+            if leaf_count <= max_colors {
+                break 'reduce;
+            }
+            let children: Vec<usize> = nodes[node].children.iter().flatten().copied().collect();
+            let merged = children.iter().filter(|&&child| nodes[child].leaf).count();
+            for child in children {
+                nodes[child].leaf = false;
+            }
+            nodes[node].children = [None; 8];
+            nodes[node].leaf = true;
+            leaf_count = leaf_count + 1 - merged;
+        }
+    }
+
+    let mut palette = Vec::with_capacity(leaf_count);
+    let mut stack = vec![0];
+    while let Some(node) = stack.pop() {
+        let node = &nodes[node];
+        if node.leaf {
+            palette.push(
+                node.sums
+                    .map(|sum| ((sum + node.count / 2) / node.count.max(1)) as u8),
+            );
+        } else {
+            stack.extend(node.children.iter().flatten());
+        }
+    }
+    palette
+}
+
+// NeuQuant as published by Anthony Dekker in 1994, in floating point. The network is a ring of
+// neurons, one per palette color, pulled towards the training pixels along with their
+// neighbours, while a bias keeps every neuron in use.
+const NEUQUANT_CYCLES: usize = 100;
+const NEUQUANT_PRIMES: [usize; 4] = [499, 491, 487, 503];
+const NEUQUANT_BETA: f64 = 1.0 / 1024.0;
+const NEUQUANT_GAMMA: f64 = 1024.0;
+const NEUQUANT_RADIUS_DECREASE: f64 = 30.0;
+const NEUQUANT_MIN_PIXELS: usize = 503;
+
+// A hash of the color, to put colors in an order unrelated to their values.
+fn scramble(color: [u8; 3]) -> u32 {
+    let mut x = u32::from_be_bytes([0, color[0], color[1], color[2]]).wrapping_mul(0x9E37_79B9);
+    x ^= x >> 15;
+    x.wrapping_mul(0x85EB_CA6B)
+}
+
+struct NeuQuant {
+    network: Vec<[f64; 3]>,
+    frequencies: Vec<f64>,
+    biases: Vec<f64>,
+}
+
+impl NeuQuant {
+    // The neuron that wins the pixel, after updating every neuron's frequency and bias.
+    fn contest(&mut self, pixel: [f64; 3]) -> usize {
+        let mut best_bias_distance = f64::MAX;
+        let mut best_bias_position = 0;
+        let mut best_distance = f64::MAX;
+        let mut best_position = 0;
+        for i in 0..self.network.len() {
+            let neuron = self.network[i];
+            let distance = (neuron[0] - pixel[0]).abs()
+                + (neuron[1] - pixel[1]).abs()
+                + (neuron[2] - pixel[2]).abs();
+            if distance < best_distance {
+                best_distance = distance;
+                best_position = i;
+            }
+            let bias_distance = distance - self.biases[i];
+            if bias_distance < best_bias_distance {
+                best_bias_distance = bias_distance;
+                best_bias_position = i;
+            }
+            let beta_frequency = self.frequencies[i] * NEUQUANT_BETA;
+            self.frequencies[i] -= beta_frequency;
+            self.biases[i] += beta_frequency * NEUQUANT_GAMMA;
+        }
+        self.frequencies[best_position] += NEUQUANT_BETA;
+        self.biases[best_position] -= NEUQUANT_BETA * NEUQUANT_GAMMA;
+        best_bias_position
+    }
+
+    fn move_towards(&mut self, neuron: usize, pixel: [f64; 3], amount: f64) {
+        for (value, channel) in self.network[neuron].iter_mut().zip(pixel) {
+            *value -= amount * (*value - channel);
+        }
+    }
+
+    fn learn(&mut self, pixels: &[[u8; 3]], sample_factor: u32) {
+        let size = self.network.len();
+        let sample_count = (pixels.len() / sample_factor as usize).max(1);
+        let delta = (sample_count / NEUQUANT_CYCLES).max(1);
+        let alpha_decrease = 30.0 + (sample_factor as f64 - 1.0) / 3.0;
+        let mut alpha = 1.0;
+        let mut radius = (size / 8) as f64;
+        let step = NEUQUANT_PRIMES
+            .iter()
+            .copied()
+            .find(|&prime| !pixels.len().is_multiple_of(prime))
+            .unwrap_or(1);
+
+        let mut position = 0;
+        for i in 1..=sample_count {
+            let pixel = pixels[position].map(|channel| channel as f64);
+            let winner = self.contest(pixel);
+            self.move_towards(winner, pixel, alpha);
+
+            let reach = radius as usize;
+            if reach > 1 {
+                for offset in 1..reach {
+                    let pull =
+                        alpha * (reach * reach - offset * offset) as f64 / (reach * reach) as f64;
+                    if winner + offset < size {
+                        self.move_towards(winner + offset, pixel, pull);
+                    }
+                    if offset <= winner {
+                        self.move_towards(winner - offset, pixel, pull);
+                    }
+                }
+            }
+
+            position = (position + step) % pixels.len();
+            if i.is_multiple_of(delta) {
+                alpha -= alpha / alpha_decrease;
+                radius -= radius / NEUQUANT_RADIUS_DECREASE;
+            }
+        }
+    }
+}
+
+fn neuquant(entries: &[([u8; 3], u32)], max_colors: usize, sample_factor: u32) -> Vec<[u8; 3]> {
+    let total: u64 = entries.iter().map(|&(_, count)| count as u64).sum();
+    let scale = (MAX_TRAINING_PIXELS as f64 / total as f64).min(1.0);
+    // Learning the colors in sorted order drags the whole network along one channel, so they
+    // are shuffled, always the same way.
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|&(color, _)| scramble(color));
+    let mut pixels = Vec::new();
+    for (color, count) in entries {
+        let repeats = ((count as f64 * scale).round() as usize).max(1);
+        pixels.extend(std::iter::repeat_n(color, repeats));
+    }
+    // Too few pixels to skip any, as in the reference implementation.
+    let sample_factor = if pixels.len() < NEUQUANT_MIN_PIXELS {
+        1
+    } else {
+        sample_factor
+    };
+
+    let mut network = NeuQuant {
+        network: (0..max_colors)
+            .map(|i| [(i * 256 / max_colors) as f64; 3])
+            .collect(),
+        frequencies: vec![1.0 / max_colors as f64; max_colors],
+        biases: vec![0.0; max_colors],
+    };
+    network.learn(&pixels, sample_factor);
+    network
+        .network
+        .iter()
+        .map(|neuron| neuron.map(|channel| channel.round().clamp(0.0, 255.0) as u8))
+        .collect()
+}
@@ -0,0 +1,200 @@
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+use super::{EncodeOptions, GifError, ImageData, GIF};
+
+#[derive(Clone, Copy)]
+pub enum ResizeFilter {
+    // Keeps hard pixel edges, best for pixel art. Also the only filter that keeps the original
+    // color tables, since no new colors are made.
+    Nearest,
+    Bilinear,
+    Lanczos3,
+}
+
+#[derive(Clone, Copy)]
+pub enum ResizeMode {
+    // Scales to exactly the requested size, distorting the picture if the aspect ratio differs.
+    Stretch,
+    // Scales uniformly to the largest size that fits the requested size. The result can be
+    // smaller than requested in one direction.
+    KeepAspect,
+    // Like KeepAspect, but centered on a transparent canvas of exactly the requested size.
+    Fit,
+    // Scales uniformly to cover the requested size and crops what sticks out, keeping the center.
+    Fill,
+}
+
+pub struct ResizeOptions {
+    pub filter: ResizeFilter,
+    pub mode: ResizeMode,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> ResizeOptions {
+        ResizeOptions {
+            filter: ResizeFilter::Lanczos3,
+            mode: ResizeMode::KeepAspect,
+        }
+    }
+}
+
+impl ResizeFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Bilinear => FilterType::Triangle,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+// Maps a pixel on the resized canvas back to the source canvas pixel it samples.
+fn source_position(position: u32, source_size: u32, target_size: u32) -> u32 {
+    (((position as f64 + 0.5) * source_size as f64 / target_size as f64) as u32)
+        .min(source_size - 1)
+}
+
+// The run of target pixels whose source pixel lies in `start..start + length`.
+fn scaled_span(start: u32, length: u32, source_size: u32, target_size: u32) -> (u32, u32) {
+    let end = start + length;
+    let mut span: Option<(u32, u32)> = None;
+    for position in 0..target_size {
+        let source = source_position(position, source_size, target_size);
+        if source >= start && source < end {
+            span = Some(match span {
+                Some((first, _)) => (first, position + 1),
+                None => (position, position + 1),
+            });
+        }
+    }
+    span.map(|(first, last)| (first, last - first)).unwrap_or((
+        ((start as u64 * target_size as u64 / source_size as u64) as u32).min(target_size),
+        0,
+    ))
+}
+
+impl GIF {
+    pub fn resize(
+        &self,
+        width: u16,
+        height: u16,
+        options: &ResizeOptions,
+    ) -> Result<GIF, GifError> {
+        let source_width = self.logical_screen_descriptor.width as u32;
+        let source_height = self.logical_screen_descriptor.height as u32;
+        if width == 0 || height == 0 || source_width == 0 || source_height == 0 {
+            return Err(GifError::InvalidInput {
+                message: format!(
+                    "Cannot resize a {}x{} gif to {}x{}",
+                    source_width, source_height, width, height
+                ),
+            });
+        }
+
+        let width = width as u32;
+        let height = height as u32;
+        let (scaled_width, scaled_height) = match options.mode {
+            ResizeMode::Stretch => (width, height),
+            ResizeMode::KeepAspect | ResizeMode::Fit | ResizeMode::Fill => {
+                let horizontal = width as f64 / source_width as f64;
+                let vertical = height as f64 / source_height as f64;
+                let scale = match options.mode {
+                    ResizeMode::Fill => horizontal.max(vertical),
+                    _ => horizontal.min(vertical),
+                };
+                (
+                    ((source_width as f64 * scale).round() as u32).clamp(1, u16::MAX as u32),
+                    ((source_height as f64 * scale).round() as u32).clamp(1, u16::MAX as u32),
+                )
+            }
+        };
+
+        if let (ResizeFilter::Nearest, ResizeMode::Stretch | ResizeMode::KeepAspect) =
+            (options.filter, options.mode)
+        {
+            return self.resize_indices(scaled_width, scaled_height);
+        }
+
+        let (canvas_width, canvas_height) = match options.mode {
+            ResizeMode::KeepAspect => (scaled_width, scaled_height),
+            _ => (width, height),
+        };
+        let x = (canvas_width as i64 - scaled_width as i64) / 2;
+        let y = (canvas_height as i64 - scaled_height as i64) / 2;
+
+        let frames: Vec<RgbaImage> = self
+            .frames()?
+            .iter()
+            .map(|frame| {
+                let scaled = imageops::resize(
+                    frame,
+                    scaled_width,
+                    scaled_height,
+                    options.filter.filter_type(),
+                );
+                if (scaled_width, scaled_height) == (canvas_width, canvas_height) {
+                    return scaled;
+                }
+                let mut canvas = RgbaImage::new(canvas_width, canvas_height);
+                imageops::replace(&mut canvas, &scaled, x, y);
+                canvas
+            })
+            .collect();
+
+        let resized = GIF::from_frames(&frames, &self.delays(), &EncodeOptions::default())?;
+        Ok(resized.with_file_extensions_of(self))
+    }
+
+    // Nearest neighbour scaling done on the color indices of every frame, so frames keep their
+    // color tables, transparency and disposal, and only their rectangles are scaled.
+    fn resize_indices(&self, width: u32, height: u32) -> Result<GIF, GifError> {
+        let source_width = self.logical_screen_descriptor.width as u32;
+        let source_height = self.logical_screen_descriptor.height as u32;
+
+        let mut resized = self.clone();
+        resized.logical_screen_descriptor.width = width as u16;
+        resized.logical_screen_descriptor.height = height as u16;
+
+        for image in &mut resized.images {
+            let descriptor = &image.image_descriptor;
+            let frame_width = descriptor.width as u32;
+            let indices = image.indices()?;
+
+            let (left, new_width) = scaled_span(
+                descriptor.left_position as u32,
+                frame_width,
+                source_width,
+                width,
+            );
+            let (top, new_height) = scaled_span(
+                descriptor.top_position as u32,
+                descriptor.height as u32,
+                source_height,
+                height,
+            );
+
+            let mut new_indices = Vec::with_capacity((new_width * new_height) as usize);
+            for y in top..top + new_height {
+                let source_y =
+                    source_position(y, source_height, height) - descriptor.top_position as u32;
+                for x in left..left + new_width {
+                    let source_x =
+                        source_position(x, source_width, width) - descriptor.left_position as u32;
+                    new_indices.push(indices[(source_y * frame_width + source_x) as usize]);
+                }
+            }
+
+            let descriptor = &mut image.image_descriptor;
+            descriptor.left_position = left as u16;
+            descriptor.top_position = top as u16;
+            descriptor.width = new_width as u16;
+            descriptor.height = new_height as u16;
+            descriptor.packed_fields.interlace_flag = false;
+            image.image_data =
+                ImageData::from_indices(image.image_data.lzw_minimum_code_size, &new_indices);
+        }
+
+        Ok(resized)
+    }
+}
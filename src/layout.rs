@@ -0,0 +1,154 @@
+use rusttype::{point, Font, PositionedGlyph, Scale};
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+
+const MIN_FONT_SIZE: f32 = 4.0;
+
+#[derive(Clone, Copy)]
+pub enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+pub struct LayoutOptions {
+    // Lines are wrapped at line break opportunities to stay within this width. A single word
+    // wider than this still overflows.
+    pub max_width: Option<f32>,
+    pub alignment: Alignment,
+    // Multiplier of the font's natural line height.
+    pub line_spacing: f32,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> LayoutOptions {
+        LayoutOptions {
+            max_width: None,
+            alignment: Alignment::Center,
+            line_spacing: 1.0,
+        }
+    }
+}
+
+// Glyphs positioned for drawing, with the top left corner of the text block at the origin.
+pub struct TextLayout<'font> {
+    pub glyphs: Vec<PositionedGlyph<'font>>,
+    pub width: f32,
+    pub height: f32,
+    // Distance from the top of the block to the baseline of the first line.
+    pub baseline: f32,
+    pub line_count: usize,
+    pub font_size: f32,
+}
+
+fn line_width(font: &Font, line: &str, scale: Scale) -> f32 {
+    font.layout(line, scale, point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+// Splits text into lines at explicit newlines and, when a maximum width is set, at the last
+// Unicode line break opportunity that keeps the line within it.
+fn wrap<'t>(font: &Font, text: &'t str, scale: Scale, max_width: Option<f32>) -> Vec<&'t str> {
+    let trim = |line: &'t str| line.trim_end_matches(|c: char| c.is_whitespace());
+
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut last_fit: Option<usize> = None;
+    for (index, opportunity) in linebreaks(text) {
+        if let Some(max_width) = max_width {
+            if line_width(font, trim(&text[line_start..index]), scale) > max_width {
+                if let Some(fit) = last_fit {
+                    lines.push(trim(&text[line_start..fit]));
+                    line_start = fit;
+                }
+            }
+        }
+        last_fit = Some(index);
+        if opportunity == BreakOpportunity::Mandatory {
+            lines.push(trim(&text[line_start..index]));
+            line_start = index;
+            last_fit = None;
+        }
+    }
+    lines
+}
+
+pub fn layout_text<'font>(
+    font: &Font<'font>,
+    text: &str,
+    font_size: f32,
+    options: &LayoutOptions,
+) -> TextLayout<'font> {
+    let scale = Scale::uniform(font_size);
+    let v_metrics = font.v_metrics(scale);
+    let line_height =
+        (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap) * options.line_spacing;
+
+    let lines = wrap(font, text, scale, options.max_width);
+    let widths: Vec<f32> = lines
+        .iter()
+        .map(|line| line_width(font, line, scale))
+        .collect();
+    let width = widths.iter().cloned().fold(0.0, f32::max);
+
+    let mut glyphs = Vec::new();
+    for (i, (line, line_width)) in lines.iter().zip(&widths).enumerate() {
+        let x = match options.alignment {
+            Alignment::Left => 0.0,
+            Alignment::Center => (width - line_width) / 2.0,
+            Alignment::Right => width - line_width,
+        };
+        let y = v_metrics.ascent + i as f32 * line_height;
+        glyphs.extend(font.layout(line, scale, point(x, y)));
+    }
+
+    let height = if lines.is_empty() {
+        0.0
+    } else {
+        v_metrics.ascent - v_metrics.descent + (lines.len() - 1) as f32 * line_height
+    };
+
+    TextLayout {
+        glyphs,
+        width,
+        height,
+        baseline: v_metrics.ascent,
+        line_count: lines.len(),
+        font_size,
+    }
+}
+
+// The largest font size, up to `max_font_size`, at which the wrapped text fits the box.
+pub fn fit_font_size(
+    font: &Font,
+    text: &str,
+    max_font_size: f32,
+    box_width: f32,
+    box_height: f32,
+    options: &LayoutOptions,
+) -> f32 {
+    let fits = |font_size: f32| {
+        let options = LayoutOptions {
+            max_width: Some(box_width),
+            ..*options
+        };
+        let layout = layout_text(font, text, font_size, &options);
+        layout.width <= box_width && layout.height <= box_height
+    };
+
+    if fits(max_font_size) {
+        return max_font_size;
+    }
+    let mut low = MIN_FONT_SIZE;
+    let mut high = max_font_size;
+    while high - low > 0.5 {
+        let middle = (low + high) / 2.0;
+        if fits(middle) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
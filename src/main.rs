@@ -1,9 +1,10 @@
-#![allow(unused_imports, dead_code)]
+#![allow(unused_imports, dead_code, clippy::upper_case_acronyms)]
 use std::env;
-use gif::GIF;
+use gif::{CaptionStyle, GIF};
 use rusttype::Font;
 use image::{Rgb, RgbImage};
 
+mod layout;
 mod text_to_image;
 
 mod gif;
@@ -25,8 +26,22 @@ fn main() {
     // let font_data: Vec<u8> = std::fs::read(font_file_path).expect("Unable to read font file");
     // let font = Font::try_from_bytes(&font_data).expect("Unable to create font from bytes");
 
-    // text_to_image::text_to_image(font, text_to_render, font_size);
+    // text_to_image::text_to_image(&font, text_to_render, font_size, &Default::default())
+    //     .image
+    //     .save("output.png")
+    //     .expect("Unable to save image");
 
-    let gif = GIF::from_file("loading.gif");
-    gif.reverse().save("loading-reversed.gif");
+    let args: Vec<String> = env::args().collect();
+
+    let gif_file_path = args.get(1).expect("Missing argument: gif file path");
+
+    let text_to_render = args.get(2).expect("Missing argument: text to render");
+
+    let output_file_path = args.get(3).map(String::as_str).unwrap_or("output.gif");
+
+    let gif = GIF::from_file(gif_file_path).expect("Unable to read gif file");
+    gif.caption(text_to_render, &CaptionStyle::default())
+        .expect("Unable to caption gif")
+        .save(output_file_path)
+        .expect("Unable to write gif file");
 }
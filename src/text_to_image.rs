@@ -1,90 +1,187 @@
-use image::{DynamicImage, Rgba, GenericImageView};
+use image::{DynamicImage, Rgba, RgbaImage, GenericImageView};
 use rusttype::{Scale, point, Font};
 
-use crate::gif::Color;
+use crate::gif::{Color, ImageData, PaletteIndex};
+use crate::layout::{layout_text, LayoutOptions, TextLayout};
 
-pub fn text_to_image(font: Font, text_to_render: &str, font_size: f32) {
+pub struct TextOptions {
+    pub color: Rgba<u8>,
+    // None leaves the background transparent.
+    pub background: Option<Rgba<u8>>,
+    // Space around the text on every side. None uses a tenth of the font size.
+    pub padding: Option<u32>,
+}
+
+impl Default for TextOptions {
+    fn default() -> TextOptions {
+        TextOptions {
+            color: Rgba([0, 0, 0, 255]),
+            background: Some(Rgba([255, 255, 255, 255])),
+            padding: None,
+        }
+    }
+}
+
+pub struct RenderedText {
+    pub image: RgbaImage,
+    // Distance from the top of the image to the baseline of the first line.
+    pub baseline: u32,
+    // Where the text block sits in the image, as left, top, width and height.
+    pub bounds: (u32, u32, u32, u32),
+}
 
-    let scale = Scale::uniform(font_size);
-    let color = (0, 0, 0);
+pub fn text_to_image(font: &Font, text_to_render: &str, font_size: f32, options: &TextOptions) -> RenderedText {
+    let layout = layout_text(font, text_to_render, font_size, &LayoutOptions::default());
+    render_layout(&layout, options)
+}
 
-    let padding = (font_size * 0.2).ceil() as u32;
+pub fn render_layout(layout: &TextLayout, options: &TextOptions) -> RenderedText {
 
-    let v_metrics = font.v_metrics(scale);
-    let glyphs: Vec<_> = font
-        .layout(&text_to_render, scale, point(0.0, v_metrics.ascent))
-        .collect();
+    let padding = options.padding.unwrap_or((layout.font_size * 0.1).ceil() as u32);
+    let color = options.color;
 
-    let glyphs_height = (v_metrics.ascent - v_metrics.descent).ceil() as u32;
-    let glyphs_width = glyphs
-        .iter()
-        .rev()
-        .map(|g| g.position().x as f32 + g.unpositioned().h_metrics().advance_width)
-        .next()
-        .unwrap_or(0.0)
-        .ceil() as u32;
+    let glyphs_height = layout.height.ceil() as u32;
+    let glyphs_width = layout.width.ceil() as u32;
 
-    let mut image = DynamicImage::new_rgba8(glyphs_width + padding, glyphs_height + padding).to_rgba8();
+    let mut image = DynamicImage::new_rgba8(glyphs_width + 2 * padding, glyphs_height + 2 * padding).to_rgba8();
 
-    for glyph in glyphs {
+    for glyph in &layout.glyphs {
         if let Some(bounding_box) = glyph.pixel_bounding_box() {
             glyph.draw(|x, y, v| {
-                image.put_pixel(
-                    (padding / 2) + x + bounding_box.min.x as u32,
-                    (padding / 2) + y + bounding_box.min.y as u32,
-                    Rgba([color.0, color.1, color.2, (v * 255.0) as u8]),
-                )
+                let x = padding as i32 + x as i32 + bounding_box.min.x;
+                let y = padding as i32 + y as i32 + bounding_box.min.y;
+                if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+                    // Glyph boxes overlap in kerned pairs, so keep the coverage of whichever
+                    // glyph covers the pixel most instead of letting the later one erase it.
+                    let pixel = image.get_pixel_mut(x as u32, y as u32);
+                    let alpha = pixel[3].max((v * color[3] as f32) as u8);
+                    *pixel = Rgba([color[0], color[1], color[2], alpha]);
+                }
             });
         }
     }
 
-    for x in 0..image.width() {
-        for y in 0..image.height() {
-            let pixel = image.get_pixel(x, y);
-            if pixel[3] == 0 {
-                image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
-            }
+    if let Some(background) = options.background {
+        for pixel in image.pixels_mut() {
+            let mut blended = background;
+            image::Pixel::blend(&mut blended, pixel);
+            *pixel = blended;
         }
     }
 
-    image.save("output.png").unwrap();
+    RenderedText {
+        image,
+        baseline: padding + layout.baseline.round() as u32,
+        bounds: (padding, padding, glyphs_width, glyphs_height),
+    }
 }
-pub fn image_to_lzw(image: &DynamicImage, colors: &Vec<Color>) -> Vec<u8> {
-    let mut lzw = Vec::new();
-
-    let mut color_map = Vec::new();
-    for color in colors {
-        color_map.push(color.red);
-        color_map.push(color.green);
-        color_map.push(color.blue);
+
+// Draws an outline around rendered text. The image grows by the stroke width on every side so
+// the outline is never cut off.
+pub fn outline(text: &RgbaImage, stroke_width: u32, stroke_color: Rgba<u8>) -> RgbaImage {
+    if stroke_width == 0 {
+        return text.clone();
     }
 
-    let mut color_map = color_map.chunks(3);
+    let radius = stroke_width as i32;
+    let width = text.width() + 2 * stroke_width;
+    let height = text.height() + 2 * stroke_width;
+
+    // Each neighbour within the stroke radius spreads its coverage, fading out over the last
+    // pixel so the outline edge stays anti-aliased.
+    let mut offsets = Vec::new();
+    for dy in -radius - 1..=radius + 1 {
+        for dx in -radius - 1..=radius + 1 {
+            let distance = ((dx * dx + dy * dy) as f32).sqrt();
+            let weight = (radius as f32 + 0.5 - distance).clamp(0.0, 1.0);
+            if weight > 0.0 {
+                offsets.push((dx, dy, weight));
+            }
+        }
+    }
 
-    let mut current_byte = 0;
-    let mut current_bit = 0;
+    let mut image = RgbaImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut stroke_alpha: f32 = 0.0;
+            for &(dx, dy, weight) in &offsets {
+                let text_x = x as i32 + dx - radius;
+                let text_y = y as i32 + dy - radius;
+                if text_x < 0 || text_y < 0 || text_x >= text.width() as i32 || text_y >= text.height() as i32 {
+                    continue;
+                }
+                let coverage = text.get_pixel(text_x as u32, text_y as u32)[3] as f32 / 255.0;
+                stroke_alpha = stroke_alpha.max(coverage * weight);
+            }
+
+            let fill = if x >= stroke_width && y >= stroke_width && x - stroke_width < text.width() && y - stroke_width < text.height() {
+                *text.get_pixel(x - stroke_width, y - stroke_width)
+            } else {
+                Rgba([0, 0, 0, 0])
+            };
+
+            let mut pixel = Rgba([
+                stroke_color[0],
+                stroke_color[1],
+                stroke_color[2],
+                (stroke_alpha * stroke_color[3] as f32) as u8,
+            ]);
+            image::Pixel::blend(&mut pixel, &fill);
+            image.put_pixel(x, y, pixel);
+        }
+    }
+
+    image
+}
+
+pub fn image_to_lzw(image: &DynamicImage, colors: &[Color]) -> ImageData {
+    let mut indices = Vec::with_capacity((image.width() * image.height()) as usize);
+    let mut palette = PaletteIndex::new(colors);
 
     for pixel in image.pixels() {
         let pixel = pixel.2;
 
-        let mut color_index = 0;
-        for (i, color) in color_map.clone().enumerate() {
-            if color[0] == pixel[0] && color[1] == pixel[1] && color[2] == pixel[2] {
-                color_index = i;
-                break;
-            }
-        }
+        indices.push(palette.nearest([pixel[0], pixel[1], pixel[2]]));
+    }
 
-        current_byte |= (color_index << current_bit) as u8;
+    ImageData::from_indices(ImageData::minimum_code_size(colors.len()), &indices)
+}
 
-        current_bit += 2;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_glyphs_keep_their_coverage() {
+        let font = Font::try_from_bytes(include_bytes!("../res/fonts/Arial.ttf")).unwrap();
+        let options = TextOptions {
+            color: Rgba([0, 0, 0, 255]),
+            background: None,
+            padding: Some(0),
+        };
+
+        for text in ["AV", "AVAWAY Tokyo", "WAVY fjord"] {
+            let layout = layout_text(&font, text, 48.0, &LayoutOptions::default());
+            let rendered = render_layout(&layout, &options).image;
+
+            // Each glyph drawn on its own, keeping the highest coverage of any of them.
+            let mut expected = RgbaImage::new(rendered.width(), rendered.height());
+            for glyph in &layout.glyphs {
+                if let Some(bounding_box) = glyph.pixel_bounding_box() {
+                    glyph.draw(|x, y, v| {
+                        let x = x as i32 + bounding_box.min.x;
+                        let y = y as i32 + bounding_box.min.y;
+                        if x >= 0 && y >= 0 && (x as u32) < expected.width() && (y as u32) < expected.height() {
+                            let pixel = expected.get_pixel_mut(x as u32, y as u32);
+                            pixel[3] = pixel[3].max((v * 255.0) as u8);
+                        }
+                    });
+                }
+            }
 
-        if current_bit == 8 {
-            lzw.push(current_byte);
-            current_byte = 0;
-            current_bit = 0;
+            for (rendered, expected) in rendered.pixels().zip(expected.pixels()) {
+                assert_eq!(rendered[3], expected[3], "coverage lost in {:?}", text);
+            }
         }
     }
-
-    lzw
-}
\ No newline at end of file
+}